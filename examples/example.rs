@@ -1,22 +1,23 @@
 use bluez_async::BluetoothSession;
-use cloudbbq::{find_devices, BBQDevice, TemperatureUnit};
-use eyre::Report;
-use futures::select;
+use cloudbbq::{scan, BBQDevice, BBQEvent, RealTimeData, TemperatureUnit};
+use eyre::{bail, Report};
 use futures::stream::StreamExt;
+use std::env;
 use std::time::Duration;
 use tokio::time;
 
 const SCAN_DURATION: Duration = Duration::from_secs(5);
 const WAIT_DURATION: Duration = Duration::from_secs(5);
+const TARGET_TEMPERATURE: f32 = 35.0;
 
 #[tokio::main]
 async fn main() -> Result<(), Report> {
     pretty_env_logger::init();
 
+    let unit = parse_unit()?;
+
     let (_, bt_session) = BluetoothSession::new().await?;
-    bt_session.start_discovery().await?;
-    time::sleep(SCAN_DURATION).await;
-    let devices = find_devices(&bt_session).await?;
+    let devices = scan(&bt_session, SCAN_DURATION).await?;
     if devices.is_empty() {
         println!("No devices found");
         return Ok(());
@@ -29,27 +30,60 @@ async fn main() -> Result<(), Report> {
     let device = BBQDevice::new(bt_session, device_id).await?;
     device.authenticate().await?;
 
-    let mut setting_results = device.setting_results().await?.fuse();
+    let mut events = device.events().await?.boxed();
     device.request_battery_level().await?;
 
-    println!("Setting unit");
-    device
-        .set_temperature_unit(TemperatureUnit::Celcius)
-        .await?;
+    println!("Setting unit to {}", unit);
+    device.set_temperature_unit(unit).await?;
 
-    device.set_target_temp(0, 35.0).await?;
+    println!(
+        "Setting target for probe 0 to {}",
+        format_temperature(TARGET_TEMPERATURE, unit)
+    );
+    device.set_target_temp(0, TARGET_TEMPERATURE).await?;
 
-    let mut real_time_data = device.real_time().await?.fuse();
     device.enable_real_time_data(true).await?;
 
     println!("Events:");
-    loop {
-        select! {
-            data = real_time_data.select_next_some() => println!("Realtime data: {:?}", data),
-            result = setting_results.select_next_some() => println!("Setting result: {:?}", result),
-            complete => break,
-        };
+    while let Some(event) = events.next().await {
+        match event {
+            BBQEvent::RealTime(data) => {
+                println!("Realtime data: {}", format_real_time(&data, unit))
+            }
+            BBQEvent::Setting(result) => println!("Setting result: {:?}", result),
+        }
     }
 
     Ok(())
 }
+
+/// Parse the temperature unit from a `--unit <unit>` command-line flag, defaulting to Celcius.
+fn parse_unit() -> Result<TemperatureUnit, Report> {
+    let mut args = env::args().skip(1);
+    let mut unit = TemperatureUnit::Celcius;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--unit" => match args.next() {
+                Some(value) => unit = value.parse()?,
+                None => bail!("--unit requires a value"),
+            },
+            _ => bail!("Unexpected argument {:?}", arg),
+        }
+    }
+    Ok(unit)
+}
+
+fn format_temperature(celsius: f32, unit: TemperatureUnit) -> String {
+    format!("{:.1}{}", unit.convert_from_celsius(celsius), unit)
+}
+
+fn format_real_time(data: &RealTimeData, unit: TemperatureUnit) -> String {
+    data.probe_temperatures
+        .iter()
+        .map(|temperature| match temperature {
+            Some(temperature) => format_temperature(*temperature, unit),
+            None => "-".to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,108 @@
+use bluez_async::BluetoothSession;
+use cloudbbq::{scan, with_reconstructed_times, write_history_csv, BBQDevice, Error};
+use eyre::{bail, eyre, Report};
+use futures::stream::StreamExt;
+use std::env;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::{Duration, SystemTime};
+use tokio::time;
+
+const SCAN_DURATION: Duration = Duration::from_secs(5);
+const WAIT_DURATION: Duration = Duration::from_secs(5);
+/// How long to wait for the device to finish sending its history, in case it never sends the empty
+/// frame which marks the end.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Download the history recorded by the first device found, and write it to a CSV file.
+///
+/// Usage: `history <output.csv> [--interval <seconds>] [--history-command <id>]`
+///
+/// No command to request history is known for the standard profile, so `--history-command` must
+/// give the command ID for your device, e.g. `0x1C`.
+#[tokio::main]
+async fn main() -> Result<(), Report> {
+    pretty_env_logger::init();
+
+    let args = parse_args()?;
+
+    let (_, bt_session) = BluetoothSession::new().await?;
+    let devices = scan(&bt_session, SCAN_DURATION).await?;
+    if devices.is_empty() {
+        println!("No devices found");
+        return Ok(());
+    }
+    let device_id = devices[0].id.clone();
+    bt_session.connect(&device_id).await?;
+    time::sleep(WAIT_DURATION).await;
+
+    let device = BBQDevice::new(bt_session, device_id).await?;
+    device.authenticate().await?;
+    if let Some(command) = args.history_command {
+        let mut profile = device.profile();
+        profile.request_history_command = Some(command);
+        device.set_profile(profile);
+    }
+
+    let history = device.history().await?;
+    match device.request_history().await {
+        Err(Error::UnsupportedCommand(_)) => {
+            bail!("No command to request history is known, try passing --history-command")
+        }
+        result => result?,
+    }
+    println!("Downloading history...");
+    let records: Vec<_> = history
+        .take_until(time::sleep(DOWNLOAD_TIMEOUT))
+        .collect()
+        .await;
+    let records = with_reconstructed_times(records, args.interval, SystemTime::now());
+
+    let mut writer = BufWriter::new(File::create(&args.output)?);
+    write_history_csv(&mut writer, &records)?;
+    writer.flush()?;
+
+    println!("Wrote {} records to {}", records.len(), args.output);
+    if let (Some((first, _)), Some((last, _))) = (records.first(), records.last()) {
+        let span = last.duration_since(*first)?;
+        println!("History spans {} minutes", span.as_secs() / 60);
+    }
+
+    Ok(())
+}
+
+struct Args {
+    output: String,
+    interval: Duration,
+    history_command: Option<u8>,
+}
+
+fn parse_args() -> Result<Args, Report> {
+    let mut args = env::args().skip(1);
+    let mut output = None;
+    let mut interval = DEFAULT_INTERVAL;
+    let mut history_command = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--interval" => match args.next() {
+                Some(value) => interval = Duration::from_secs(value.parse()?),
+                None => bail!("--interval requires a value"),
+            },
+            "--history-command" => match args.next() {
+                Some(value) => {
+                    let hex = value.trim_start_matches("0x");
+                    history_command = Some(u8::from_str_radix(hex, 16)?);
+                }
+                None => bail!("--history-command requires a value"),
+            },
+            _ if output.is_none() => output = Some(arg),
+            _ => bail!("Unexpected argument {:?}", arg),
+        }
+    }
+    Ok(Args {
+        output: output.ok_or_else(|| eyre!("Missing output path"))?,
+        interval,
+        history_command,
+    })
+}
@@ -0,0 +1,93 @@
+//! Serve readings from the first thermometer found as Server-Sent Events, for use by a web
+//! dashboard.
+//!
+//! Run with `cargo run --example sse --features serde -- [bind address]`, then open e.g.
+//! `http://127.0.0.1:8080/` with `new EventSource(...)` in a browser. Each event is a JSON-encoded
+//! `RealTimeData`.
+
+use cloudbbq::BBQDevice;
+use eyre::Report;
+use futures::stream::StreamExt;
+use std::env;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+const SCAN_DURATION: Duration = Duration::from_secs(5);
+const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1:8080";
+
+#[tokio::main]
+async fn main() -> Result<(), Report> {
+    pretty_env_logger::init();
+
+    let bind_address = env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_BIND_ADDRESS.to_owned());
+
+    let device = BBQDevice::find_first(SCAN_DURATION).await?;
+    let readings = device.real_time().await?;
+    device.enable_real_time_data(true).await?;
+
+    let (sender, _) = broadcast::channel(16);
+    let forward_sender = sender.clone();
+    tokio::spawn(async move {
+        futures::pin_mut!(readings);
+        while let Some(reading) = readings.next().await {
+            match serde_json::to_string(&reading) {
+                // It doesn't matter if there are no clients connected.
+                Ok(json) => drop(forward_sender.send(json)),
+                Err(e) => eprintln!("Failed to encode reading: {}", e),
+            }
+        }
+    });
+
+    let listener = TcpListener::bind(&bind_address).await?;
+    println!("Serving readings on http://{}/", bind_address);
+    loop {
+        let (socket, address) = listener.accept().await?;
+        let events = sender.subscribe();
+        tokio::spawn(async move {
+            if let Err(e) = serve_events(socket, events).await {
+                println!("Client {} disconnected: {}", address, e);
+            }
+        });
+    }
+}
+
+/// Respond to an HTTP request with a stream of events, regardless of what was requested.
+async fn serve_events(
+    mut socket: TcpStream,
+    mut events: broadcast::Receiver<String>,
+) -> Result<(), Report> {
+    // Read (and ignore) the request headers.
+    let mut request = vec![];
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let length = socket.read(&mut buffer).await?;
+        if length == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..length]);
+    }
+    socket
+        .write_all(
+            b"HTTP/1.1 200 OK\r\n\
+              Content-Type: text/event-stream\r\n\
+              Cache-Control: no-cache\r\n\
+              Access-Control-Allow-Origin: *\r\n\r\n",
+        )
+        .await?;
+    loop {
+        match events.recv().await {
+            Ok(json) => {
+                socket
+                    .write_all(format!("data: {}\n\n", json).as_bytes())
+                    .await?
+            }
+            // Skip readings which this client was too slow to receive.
+            Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
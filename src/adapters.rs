@@ -0,0 +1,399 @@
+//! Adapters for streams of readings from a device.
+
+use crate::{Error, ProbeReading, RealTimeData};
+use futures::stream::{self, Stream, StreamExt};
+use futures::{future, select, FutureExt};
+use std::time::Duration;
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+/// Extension methods for streams of `RealTimeData`, such as those returned by
+/// `BBQDevice::real_time`.
+pub trait RealTimeStreamExt: Stream<Item = RealTimeData> + Sized {
+    /// Resample the stream at a fixed interval, regardless of how often the device sends readings.
+    ///
+    /// At the end of each interval this emits the most recent reading received during it, or `None`
+    /// if there wasn't one. The stream ends when the underlying stream does.
+    ///
+    /// This must be called from within a Tokio runtime.
+    fn sampled(self, interval: Duration) -> impl Stream<Item = Option<RealTimeData>> {
+        let mut ticks = time::interval_at(Instant::now() + interval, interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        stream::unfold(
+            (Box::pin(self.fuse()), ticks),
+            |(mut readings, mut ticks)| async move {
+                let mut latest = None;
+                loop {
+                    select! {
+                        reading = readings.next() => match reading {
+                            Some(reading) => latest = Some(reading),
+                            None => return None,
+                        },
+                        _ = ticks.tick().fuse() => return Some((latest, (readings, ticks))),
+                    }
+                }
+            },
+        )
+    }
+
+    /// Apply exponential smoothing to each probe's temperature, to give smoother-looking graphs than
+    /// the 0.1° steps which the device reports.
+    ///
+    /// The values produced are estimates derived from the readings, not values reported by the
+    /// device. Each smoothed value is `alpha * reading + (1 - alpha) * previous`, so smaller values
+    /// of `alpha` give smoother but slower-reacting output. When a probe is disconnected its
+    /// smoothing is reset, so it starts again from its first reading after it is reconnected.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `alpha` is not greater than 0 and at most 1.
+    fn smoothed(self, alpha: f32) -> impl Stream<Item = RealTimeData> {
+        assert!(alpha > 0.0 && alpha <= 1.0, "alpha must be in (0, 1]");
+        self.scan(Vec::<Option<f32>>::new(), move |smoothed, reading| {
+            smoothed.resize(reading.probe_temperatures.len(), None);
+            for (smoothed, temperature) in smoothed.iter_mut().zip(&reading.probe_temperatures) {
+                *smoothed = match (*smoothed, temperature) {
+                    (Some(previous), Some(temperature)) => {
+                        Some(alpha * temperature + (1.0 - alpha) * previous)
+                    }
+                    (None, Some(temperature)) => Some(*temperature),
+                    (_, None) => None,
+                };
+            }
+            future::ready(Some(RealTimeData {
+                probe_temperatures: smoothed.clone(),
+            }))
+        })
+    }
+
+    /// Apply the given function to the temperature of each connected probe in each reading, e.g.
+    /// for custom filtering or calibration. The function is passed the probe index and its
+    /// temperature.
+    ///
+    /// Disconnected probes are left as `None`, so unlike a plain `StreamExt::map` this can't
+    /// accidentally lose disconnections.
+    fn map_readings<F>(self, mut f: F) -> impl Stream<Item = RealTimeData>
+    where
+        F: FnMut(usize, f32) -> f32,
+    {
+        self.map(move |reading| RealTimeData {
+            probe_temperatures: reading
+                .probe_temperatures
+                .into_iter()
+                .enumerate()
+                .map(|(index, temperature)| temperature.map(|t| f(index, t)))
+                .collect(),
+        })
+    }
+
+    /// Yield just the temperature of the given probe from each reading, or `None` if it is
+    /// disconnected or the reading doesn't include it.
+    fn probe(self, probe: u8) -> impl Stream<Item = Option<f32>> {
+        self.map(move |reading| {
+            reading
+                .probe_temperatures
+                .get(usize::from(probe))
+                .copied()
+                .flatten()
+        })
+    }
+
+    /// Yield `Error::Timeout` whenever no reading arrives for the given idle timeout, e.g. to notice
+    /// a stalled link and reconnect.
+    ///
+    /// After a timeout the stream carries on waiting, and yields another error after each further
+    /// idle timeout without a reading. The stream ends when the underlying stream does.
+    ///
+    /// This must be called from within a Tokio runtime.
+    fn with_idle_timeout(
+        self,
+        idle_timeout: Duration,
+    ) -> impl Stream<Item = Result<RealTimeData, Error>> {
+        stream::unfold(Box::pin(self), move |mut readings| async move {
+            match time::timeout(idle_timeout, readings.next()).await {
+                Ok(Some(reading)) => Some((Ok(reading), readings)),
+                Ok(None) => None,
+                Err(_) => Some((Err(Error::Timeout), readings)),
+            }
+        })
+    }
+
+    /// Return all the readings which have already been received and are waiting in the stream,
+    /// without waiting for any more.
+    ///
+    /// The device may send a burst of several queued readings at once, e.g. after reconnecting.
+    /// Readings carry no sequence number or timestamp, so consumers which timestamp them as they
+    /// are received should use this to notice such bursts and handle them explicitly (e.g. by
+    /// spreading them out over the time since the last reading).
+    ///
+    /// Streams which aren't `Unpin`, such as the one from `BBQDevice::real_time`, must be pinned
+    /// first, e.g. with `Box::pin`.
+    fn drain_pending(&mut self) -> Vec<RealTimeData>
+    where
+        Self: Unpin,
+    {
+        let mut readings = vec![];
+        while let Some(Some(reading)) = self.next().now_or_never() {
+            readings.push(reading);
+        }
+        readings
+    }
+
+    /// Merge any readings containing fewer probes than the previous one with it, keeping the
+    /// previous values for the probes missing from the shorter reading, so that probes don't appear
+    /// to vanish.
+    ///
+    /// Devices known so far always send all their probes in every frame, so this is only needed for
+    /// devices which send partial updates.
+    fn merge_partial(self) -> impl Stream<Item = RealTimeData> {
+        self.scan(Vec::<Option<f32>>::new(), |previous, reading| {
+            let mut merged = reading.probe_temperatures;
+            if merged.len() < previous.len() {
+                merged.extend_from_slice(&previous[merged.len()..]);
+            }
+            previous.clone_from(&merged);
+            future::ready(Some(RealTimeData {
+                probe_temperatures: merged,
+            }))
+        })
+    }
+
+    /// Keep track of each probe's last known temperature, so that it can still be shown (e.g.
+    /// greyed out) after the probe is disconnected.
+    ///
+    /// A probe which has never been connected has no temperature.
+    fn with_last_known(self) -> impl Stream<Item = Vec<ProbeReading>> {
+        self.scan(Vec::<Option<f32>>::new(), |last_known, reading| {
+            last_known.resize(reading.probe_temperatures.len(), None);
+            let probes = last_known
+                .iter_mut()
+                .zip(&reading.probe_temperatures)
+                .map(|(last_known, temperature)| {
+                    if temperature.is_some() {
+                        *last_known = *temperature;
+                    }
+                    ProbeReading {
+                        connected: temperature.is_some(),
+                        temperature: *last_known,
+                    }
+                })
+                .collect();
+            future::ready(Some(probes))
+        })
+    }
+}
+
+impl<S: Stream<Item = RealTimeData>> RealTimeStreamExt for S {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ByteOrder;
+
+    fn reading(temperature: f32) -> RealTimeData {
+        RealTimeData {
+            probe_temperatures: vec![Some(temperature)],
+        }
+    }
+
+    /// Return a stream which emits each reading after the given delay since the previous one, then
+    /// ends after the final delay.
+    fn delayed_readings(
+        readings: Vec<(u64, Option<RealTimeData>)>,
+    ) -> impl Stream<Item = RealTimeData> {
+        stream::iter(readings)
+            .then(|(delay_ms, reading)| async move {
+                time::sleep(Duration::from_millis(delay_ms)).await;
+                reading
+            })
+            .take_while(|reading| futures::future::ready(reading.is_some()))
+            .map(Option::unwrap)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sampled_emits_latest_per_interval() {
+        let readings = delayed_readings(vec![
+            (500, Some(reading(1.0))),
+            (2000, Some(reading(2.0))),
+            (200, Some(reading(3.0))),
+            (2800, None),
+        ]);
+        let samples: Vec<_> = readings.sampled(Duration::from_secs(1)).collect().await;
+        assert_eq!(
+            samples,
+            vec![Some(reading(1.0)), None, Some(reading(3.0)), None, None]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn with_idle_timeout_stalled() {
+        let readings = delayed_readings(vec![
+            (500, Some(reading(1.0))),
+            (2500, Some(reading(2.0))),
+            (100, None),
+        ]);
+        let results: Vec<_> = readings
+            .with_idle_timeout(Duration::from_secs(1))
+            .map(|result| result.map_err(|e| e.to_string()))
+            .collect()
+            .await;
+        assert_eq!(
+            results,
+            vec![
+                Ok(reading(1.0)),
+                Err("Timed out".to_string()),
+                Err("Timed out".to_string()),
+                Ok(reading(2.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn drain_pending_burst() {
+        let (sender, receiver) = futures::channel::mpsc::unbounded::<Vec<u8>>();
+        let mut readings = receiver.filter_map(|value| {
+            future::ready(RealTimeData::try_parse(&value, ByteOrder::LittleEndian))
+        });
+        assert_eq!(readings.drain_pending(), vec![]);
+        for value in [[0xC8, 0x00], [0xC9, 0x00], [0xCA, 0x00]] {
+            sender.unbounded_send(value.to_vec()).unwrap();
+        }
+        assert_eq!(
+            readings.drain_pending(),
+            vec![reading(20.0), reading(20.1), reading(20.2)]
+        );
+        assert_eq!(readings.drain_pending(), vec![]);
+        sender.unbounded_send(vec![0xCB, 0x00]).unwrap();
+        assert_eq!(readings.drain_pending(), vec![reading(20.3)]);
+    }
+
+    #[tokio::test]
+    async fn single_probe() {
+        let readings = stream::iter(vec![
+            RealTimeData {
+                probe_temperatures: vec![Some(10.0), Some(20.0)],
+            },
+            RealTimeData {
+                probe_temperatures: vec![Some(11.0), None],
+            },
+            RealTimeData {
+                probe_temperatures: vec![Some(12.0)],
+            },
+        ]);
+        assert_eq!(
+            readings.probe(1).collect::<Vec<_>>().await,
+            vec![Some(20.0), None, None]
+        );
+    }
+
+    #[tokio::test]
+    async fn map_readings_preserves_disconnected() {
+        let readings = stream::iter(vec![RealTimeData {
+            probe_temperatures: vec![Some(10.0), None, Some(20.0)],
+        }]);
+        let mapped: Vec<_> = readings
+            .map_readings(|index, temperature| temperature + index as f32)
+            .collect()
+            .await;
+        assert_eq!(
+            mapped,
+            vec![RealTimeData {
+                probe_temperatures: vec![Some(10.0), None, Some(22.0)],
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn merge_partial_short_frame() {
+        let readings = stream::iter(vec![
+            RealTimeData {
+                probe_temperatures: vec![Some(20.0), None, Some(30.0), Some(40.0)],
+            },
+            RealTimeData {
+                probe_temperatures: vec![Some(21.0), Some(25.0)],
+            },
+        ]);
+        let merged: Vec<_> = readings.merge_partial().collect().await;
+        assert_eq!(
+            merged[1],
+            RealTimeData {
+                probe_temperatures: vec![Some(21.0), Some(25.0), Some(30.0), Some(40.0)],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn smoothed_step() {
+        let readings = stream::iter(vec![
+            reading(0.0),
+            reading(10.0),
+            reading(10.0),
+            reading(10.0),
+        ]);
+        let smoothed: Vec<_> = readings.smoothed(0.5).collect().await;
+        assert_eq!(
+            smoothed,
+            vec![reading(0.0), reading(5.0), reading(7.5), reading(8.75)]
+        );
+    }
+
+    #[tokio::test]
+    async fn smoothed_resets_on_disconnect() {
+        let disconnected = RealTimeData {
+            probe_temperatures: vec![None],
+        };
+        let readings = stream::iter(vec![
+            reading(10.0),
+            reading(20.0),
+            disconnected.clone(),
+            reading(30.0),
+        ]);
+        let smoothed: Vec<_> = readings.smoothed(0.5).collect().await;
+        assert_eq!(
+            smoothed,
+            vec![reading(10.0), reading(15.0), disconnected, reading(30.0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn with_last_known_connect_disconnect() {
+        let readings = stream::iter(vec![
+            RealTimeData {
+                probe_temperatures: vec![None, None],
+            },
+            RealTimeData {
+                probe_temperatures: vec![Some(20.0), None],
+            },
+            RealTimeData {
+                probe_temperatures: vec![None, Some(30.0)],
+            },
+        ]);
+        let probes: Vec<_> = readings.with_last_known().collect().await;
+        let disconnected = ProbeReading {
+            connected: false,
+            temperature: None,
+        };
+        assert_eq!(
+            probes,
+            vec![
+                vec![disconnected, disconnected],
+                vec![
+                    ProbeReading {
+                        connected: true,
+                        temperature: Some(20.0),
+                    },
+                    disconnected,
+                ],
+                vec![
+                    ProbeReading {
+                        connected: false,
+                        temperature: Some(20.0),
+                    },
+                    ProbeReading {
+                        connected: true,
+                        temperature: Some(30.0),
+                    },
+                ],
+            ]
+        );
+    }
+}
@@ -0,0 +1,154 @@
+//! A blocking wrapper around `BBQDevice`, for simple scripts which don't want to deal with async
+//! code.
+
+use crate::{BBQDevice, Error, HandshakeInfo, RealTimeData, TemperatureUnit};
+use futures::{Stream, StreamExt};
+use std::io;
+use std::ops::Range;
+use std::time::Duration;
+use tokio::runtime::{Handle, Runtime};
+
+/// A blocking wrapper around a `BBQDevice`, which runs each operation to completion on a private
+/// Tokio runtime.
+///
+/// This is only a convenience for simple scripts, and only covers the most common operations. Use
+/// `device()` to get at the underlying `BBQDevice` for anything else, but note that it must then be
+/// used from within the same runtime, e.g. via `block_on`.
+///
+/// Don't use this from within an existing async runtime. `find_first` returns `Error::Runtime` if
+/// called from within one, and the other methods will panic.
+#[derive(Debug)]
+pub struct BlockingBBQDevice {
+    runtime: Runtime,
+    device: BBQDevice,
+}
+
+impl BlockingBBQDevice {
+    /// Scan for the given duration, then connect to and authenticate with the first compatible
+    /// device found, like `BBQDevice::find_first`.
+    pub fn find_first(scan_duration: Duration) -> Result<Self, Error> {
+        let runtime = new_runtime()?;
+        let device = runtime.block_on(BBQDevice::find_first(scan_duration))?;
+        Ok(Self { runtime, device })
+    }
+
+    /// Get the underlying async device.
+    pub fn device(&self) -> &BBQDevice {
+        &self.device
+    }
+
+    /// Run the given future to completion on this wrapper's runtime.
+    pub fn block_on<F: std::future::Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    /// Enable real-time data and wait up to the given timeout for a single reading.
+    pub fn read_once(&self, timeout: Duration) -> Result<RealTimeData, Error> {
+        self.block_on(async {
+            let readings = self.device.real_time().await?;
+            self.device.enable_real_time_data(true).await?;
+            first_reading(readings, timeout).await
+        })
+    }
+
+    /// Blocking version of `BBQDevice::handshake`.
+    pub fn handshake(&self) -> Result<HandshakeInfo, Error> {
+        self.block_on(self.device.handshake())
+    }
+
+    /// Blocking version of `BBQDevice::set_temperature_unit`.
+    pub fn set_temperature_unit(&self, unit: TemperatureUnit) -> Result<(), Error> {
+        Ok(self.block_on(self.device.set_temperature_unit(unit))?)
+    }
+
+    /// Blocking version of `BBQDevice::set_target_range`.
+    pub fn set_target_range(&self, probe: u8, range: Range<f32>) -> Result<(), Error> {
+        self.block_on(self.device.set_target_range(probe, range))
+    }
+
+    /// Blocking version of `BBQDevice::set_target_temp`.
+    pub fn set_target_temp(&self, probe: u8, target: f32) -> Result<(), Error> {
+        self.block_on(self.device.set_target_temp(probe, target))
+    }
+
+    /// Blocking version of `BBQDevice::remove_target`.
+    pub fn remove_target(&self, probe: u8) -> Result<(), Error> {
+        self.block_on(self.device.remove_target(probe))
+    }
+
+    /// Blocking version of `BBQDevice::request_battery_level`. The result is reported on
+    /// `BBQDevice::setting_results`; use `handshake` instead to wait for it.
+    pub fn request_battery_level(&self) -> Result<(), Error> {
+        Ok(self.block_on(self.device.request_battery_level())?)
+    }
+
+    /// Blocking version of `BBQDevice::silence_alarm`.
+    pub fn silence_alarm(&self) -> Result<(), Error> {
+        Ok(self.block_on(self.device.silence_alarm())?)
+    }
+}
+
+/// Start a private runtime for a `BlockingBBQDevice`, unless this is already within a runtime, as
+/// blocking on it there would panic.
+fn new_runtime() -> Result<Runtime, Error> {
+    if Handle::try_current().is_ok() {
+        return Err(Error::Runtime(io::Error::other(
+            "BlockingBBQDevice can't be used from within an async runtime",
+        )));
+    }
+    Runtime::new().map_err(Error::Runtime)
+}
+
+/// Wait up to the given timeout for the first of the given readings.
+async fn first_reading(
+    readings: impl Stream<Item = RealTimeData>,
+    timeout: Duration,
+) -> Result<RealTimeData, Error> {
+    futures::pin_mut!(readings);
+    tokio::time::timeout(timeout, readings.next())
+        .await
+        .map_err(|_| Error::Timeout)?
+        .ok_or(Error::StreamEnded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+
+    fn reading(temperature: f32) -> RealTimeData {
+        RealTimeData {
+            probe_temperatures: vec![Some(temperature)],
+        }
+    }
+
+    #[test]
+    fn read_first_reading() {
+        let runtime = new_runtime().unwrap();
+        let readings = stream::iter(vec![reading(20.0), reading(21.0)]);
+        assert_eq!(
+            runtime
+                .block_on(first_reading(readings, Duration::from_secs(1)))
+                .unwrap(),
+            reading(20.0)
+        );
+    }
+
+    #[test]
+    fn read_timeout() {
+        let runtime = new_runtime().unwrap();
+        assert!(matches!(
+            runtime.block_on(first_reading(stream::pending(), Duration::from_millis(10))),
+            Err(Error::Timeout)
+        ));
+        assert!(matches!(
+            runtime.block_on(first_reading(stream::empty(), Duration::from_secs(1))),
+            Err(Error::StreamEnded)
+        ));
+    }
+
+    #[tokio::test]
+    async fn runtime_within_runtime() {
+        assert!(matches!(new_runtime(), Err(Error::Runtime(_))));
+    }
+}
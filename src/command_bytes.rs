@@ -0,0 +1,423 @@
+//! Pure functions to encode the commands which `BBQDevice` writes to the device's 'setting data'
+//! characteristic.
+//!
+//! These are what `BBQDevice` uses internally, and are exposed so that the wire format can be
+//! checked against captures from other apps when reverse-engineering the protocol.
+
+use crate::{
+    decode_temperature, encode_temperature, ByteOrder, DeviceProfile, Error, TemperatureUnit,
+    BATTERY_LEVEL_PROPERTY_ID, SILENCE_ARGUMENT, TARGET_TEMP_NO_MAXIMUM, TARGET_TEMP_NO_MINIMUM,
+    UNITS_CELCIUS_ARGUMENT, UNITS_FAHRENHEIT_ARGUMENT,
+};
+use std::ops::Range;
+
+/// Encode a command to set the target temperature range for the given probe, as sent by
+/// `BBQDevice::set_target_range`.
+///
+/// If the profile only supports whole-degree targets then the range is rounded to the nearest
+/// degree.
+pub fn set_target_range(
+    profile: &DeviceProfile,
+    probe: u8,
+    range: Range<f32>,
+) -> Result<Vec<u8>, Error> {
+    // A bound meaning no alarm on one side doesn't conflict with any real alarm on the other.
+    let one_sided = range.start == TARGET_TEMP_NO_MINIMUM || range.end == TARGET_TEMP_NO_MAXIMUM;
+    if range.start > range.end && !one_sided {
+        return Err(Error::InvertedTargetRange(range));
+    }
+    let bottom_bytes = encode_temperature(profile.round_target(range.start), profile.byte_order)?;
+    let top_bytes = encode_temperature(profile.round_target(range.end), profile.byte_order)?;
+    Ok(profile.frame(&[
+        profile.set_target_temp_command,
+        probe,
+        bottom_bytes[0],
+        bottom_bytes[1],
+        top_bytes[0],
+        top_bytes[1],
+    ]))
+}
+
+/// Encode a command to set independent upper and lower target temperatures for the given probe, as
+/// sent by `BBQDevice::set_target`. A bound of `None` is sent as the value which the device treats
+/// as "no alarm on that side".
+pub fn set_target(
+    profile: &DeviceProfile,
+    probe: u8,
+    low: Option<f32>,
+    high: Option<f32>,
+) -> Result<Vec<u8>, Error> {
+    set_target_range(profile, probe, target_range(low, high))
+}
+
+/// Convert optional lower and upper bounds to a target range, using the special values for missing
+/// bounds.
+pub(crate) fn target_range(low: Option<f32>, high: Option<f32>) -> Range<f32> {
+    low.unwrap_or(TARGET_TEMP_NO_MINIMUM)..high.unwrap_or(TARGET_TEMP_NO_MAXIMUM)
+}
+
+/// Return the target range which the device will actually store if it is sent the given range,
+/// after rounding to the precision of the encoding (and to whole degrees, if the profile requires
+/// it).
+///
+/// There is no known way to read targets back from the device to verify them, so this can be used
+/// instead to check that a set-point will be stored as expected.
+pub fn stored_target_range(
+    profile: &DeviceProfile,
+    range: Range<f32>,
+) -> Result<Range<f32>, Error> {
+    let stored = |temperature| {
+        encode_temperature(profile.round_target(temperature), ByteOrder::LittleEndian)
+            .map(|bytes| decode_temperature(bytes, ByteOrder::LittleEndian))
+    };
+    Ok(stored(range.start)?..stored(range.end)?)
+}
+
+/// Encode a command to set the unit for the device's display, as sent by
+/// `BBQDevice::set_temperature_unit`.
+pub fn set_unit(profile: &DeviceProfile, unit: TemperatureUnit) -> Vec<u8> {
+    let argument = match unit {
+        TemperatureUnit::Celcius => UNITS_CELCIUS_ARGUMENT,
+        TemperatureUnit::Fahrenheit => UNITS_FAHRENHEIT_ARGUMENT,
+    };
+    profile.frame(&[profile.set_unit_command, argument])
+}
+
+/// Encode a command to enable or disable real-time data, as sent by
+/// `BBQDevice::enable_real_time_data`.
+pub fn enable_real_time_data(profile: &DeviceProfile, enable: bool) -> Vec<u8> {
+    let argument = if enable { 0x01 } else { 0x00 };
+    profile.frame(&[profile.real_time_data_command, argument])
+}
+
+/// Encode a command to request that the device report the property with the given ID.
+pub fn request_property(profile: &DeviceProfile, property_id: u8) -> Vec<u8> {
+    profile.frame(&[profile.request_property_command, property_id])
+}
+
+/// Encode a command to request the battery level, as sent by `BBQDevice::request_battery_level`.
+pub fn request_battery_level(profile: &DeviceProfile) -> Vec<u8> {
+    request_property(profile, BATTERY_LEVEL_PROPERTY_ID)
+}
+
+/// Encode a command to request the device's recorded history, as sent by `BBQDevice::request_history`.
+///
+/// Returns `Error::UnsupportedCommand` if the profile doesn't have a history command.
+pub fn request_history(profile: &DeviceProfile) -> Result<Vec<u8>, Error> {
+    let command = profile
+        .request_history_command
+        .ok_or(Error::UnsupportedCommand("request history"))?;
+    Ok(profile.frame(&[command]))
+}
+
+/// Encode a command to silence the alarm, as sent by `BBQDevice::silence_alarm`.
+pub fn silence_alarm(profile: &DeviceProfile) -> Vec<u8> {
+    profile.frame(&[profile.silence_command, SILENCE_ARGUMENT])
+}
+
+/// Describe the given setting data frame in a human-readable form, e.g.
+/// `SET_TARGET_TEMP probe=0 low=none high=35.0°C`, for comparing captures from other apps against
+/// what this crate sends.
+///
+/// This is only a debugging aid for protocol work, so it is hidden from the documentation and its
+/// output format may change in any release.
+#[doc(hidden)]
+pub fn explain_command(profile: &DeviceProfile, command: &[u8]) -> String {
+    let temperature = |bytes: [u8; 2], none: f32| {
+        let fixed = match profile.byte_order {
+            ByteOrder::LittleEndian => i16::from_le_bytes(bytes),
+            ByteOrder::BigEndian => i16::from_be_bytes(bytes),
+        };
+        let temperature = f32::from(fixed) / 10.0;
+        if temperature == none {
+            "none".to_owned()
+        } else {
+            format!("{:.1}°C", temperature)
+        }
+    };
+    match command {
+        [id, probe, low_0, low_1, high_0, high_1, ..] if *id == profile.set_target_temp_command => {
+            format!(
+                "SET_TARGET_TEMP probe={} low={} high={}",
+                probe,
+                temperature([*low_0, *low_1], TARGET_TEMP_NO_MINIMUM),
+                temperature([*high_0, *high_1], TARGET_TEMP_NO_MAXIMUM)
+            )
+        }
+        [id, argument, ..] if *id == profile.set_unit_command => match *argument {
+            UNITS_CELCIUS_ARGUMENT => "SET_UNIT celsius".to_owned(),
+            UNITS_FAHRENHEIT_ARGUMENT => "SET_UNIT fahrenheit".to_owned(),
+            argument => format!("SET_UNIT unknown={:#04x}", argument),
+        },
+        [id, argument, ..] if *id == profile.real_time_data_command => match argument {
+            0x00 => "REAL_TIME_DATA disable".to_owned(),
+            0x01 => "REAL_TIME_DATA enable".to_owned(),
+            argument => format!("REAL_TIME_DATA unknown={:#04x}", argument),
+        },
+        [id, property, ..] if *id == profile.request_property_command => match *property {
+            BATTERY_LEVEL_PROPERTY_ID => "REQUEST_PROPERTY battery".to_owned(),
+            property => format!("REQUEST_PROPERTY id={:#04x}", property),
+        },
+        [id, SILENCE_ARGUMENT, ..] if *id == profile.silence_command => "SILENCE".to_owned(),
+        [id, ..] if Some(*id) == profile.request_history_command => "REQUEST_HISTORY".to_owned(),
+        _ => format!("UNKNOWN {:02X?}", command),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STANDARD: DeviceProfile = DeviceProfile::STANDARD;
+    const CLONE: DeviceProfile = DeviceProfile::TEST_CLONE;
+
+    #[test]
+    fn set_target_range_inverted() {
+        assert!(matches!(
+            set_target_range(&STANDARD, 0, 60.0..40.0),
+            Err(Error::InvertedTargetRange(range)) if range == (60.0..40.0)
+        ));
+        assert!(set_target_range(&STANDARD, 0, 50.0..50.0).is_ok());
+    }
+
+    #[test]
+    fn set_target_low_only_above_no_maximum() {
+        assert_eq!(
+            set_target(&STANDARD, 0, Some(400.0), None).unwrap(),
+            vec![0x01, 0x00, 0xA0, 0x0F, 0xCC, 0x0B]
+        );
+        assert!(set_target_range(&STANDARD, 0, 400.0..TARGET_TEMP_NO_MAXIMUM).is_ok());
+        assert!(matches!(
+            set_target_range(&STANDARD, 0, 400.0..301.0),
+            Err(Error::InvertedTargetRange(_))
+        ));
+    }
+
+    #[test]
+    fn request_history_command() {
+        assert!(matches!(
+            request_history(&STANDARD),
+            Err(Error::UnsupportedCommand(_))
+        ));
+        let command = request_history(&CLONE).unwrap();
+        assert_eq!(command, vec![0x1C, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(explain_command(&CLONE, &command), "REQUEST_HISTORY");
+    }
+
+    #[test]
+    fn set_target_range_standard() {
+        assert_eq!(
+            set_target_range(&STANDARD, 1, 20.0..51.3).unwrap(),
+            vec![0x01, 0x01, 0xC8, 0x00, 0x01, 0x02]
+        );
+    }
+
+    #[test]
+    fn set_target_range_clone() {
+        assert_eq!(
+            set_target_range(&CLONE, 1, 20.0..51.3).unwrap(),
+            vec![0x11, 0x01, 0x00, 0xC8, 0x02, 0x01, 0x00]
+        );
+    }
+
+    #[test]
+    fn set_target_range_whole_degrees() {
+        let profile = DeviceProfile {
+            whole_degree_targets: true,
+            ..STANDARD
+        };
+        // 54.5°C rounds up to 55°C, or 550 tenths.
+        assert_eq!(
+            set_target_range(&profile, 0, 20.4..54.5).unwrap(),
+            vec![0x01, 0x00, 0xC8, 0x00, 0x26, 0x02]
+        );
+        assert_eq!(
+            set_target_range(&profile, 0, 20.0..55.0).unwrap(),
+            set_target_range(&STANDARD, 0, 20.0..55.0).unwrap()
+        );
+    }
+
+    #[test]
+    fn set_target_range_out_of_range() {
+        assert!(matches!(
+            set_target_range(&STANDARD, 0, 0.0..5000.0),
+            Err(Error::TemperatureEncodingError(t)) if t == 5000.0
+        ));
+    }
+
+    #[test]
+    fn set_target_bounds() {
+        assert_eq!(
+            set_target(&STANDARD, 0, Some(20.0), Some(51.3)).unwrap(),
+            vec![0x01, 0x00, 0xC8, 0x00, 0x01, 0x02]
+        );
+        assert_eq!(
+            set_target(&STANDARD, 0, Some(20.0), None).unwrap(),
+            vec![0x01, 0x00, 0xC8, 0x00, 0xCC, 0x0B]
+        );
+        assert_eq!(
+            set_target(&STANDARD, 0, None, Some(51.3)).unwrap(),
+            vec![0x01, 0x00, 0x48, 0xF4, 0x01, 0x02]
+        );
+        assert_eq!(
+            set_target(&STANDARD, 0, None, None).unwrap(),
+            vec![0x01, 0x00, 0x48, 0xF4, 0xCC, 0x0B]
+        );
+    }
+
+    #[test]
+    fn explain_known_commands() {
+        for (command, explanation) in [
+            (
+                set_target(&STANDARD, 0, None, Some(35.0)).unwrap(),
+                "SET_TARGET_TEMP probe=0 low=none high=35.0°C",
+            ),
+            (
+                set_target_range(&STANDARD, 2, 20.0..51.3).unwrap(),
+                "SET_TARGET_TEMP probe=2 low=20.0°C high=51.3°C",
+            ),
+            (
+                set_unit(&STANDARD, TemperatureUnit::Fahrenheit),
+                "SET_UNIT fahrenheit",
+            ),
+            (
+                enable_real_time_data(&STANDARD, true),
+                "REAL_TIME_DATA enable",
+            ),
+            (request_battery_level(&STANDARD), "REQUEST_PROPERTY battery"),
+            (
+                request_property(&STANDARD, 0x42),
+                "REQUEST_PROPERTY id=0x42",
+            ),
+            (silence_alarm(&STANDARD), "SILENCE"),
+        ] {
+            assert_eq!(explain_command(&STANDARD, &command), explanation);
+        }
+    }
+
+    #[test]
+    fn explain_clone_commands() {
+        assert_eq!(
+            explain_command(&CLONE, &set_target(&CLONE, 1, Some(20.0), None).unwrap()),
+            "SET_TARGET_TEMP probe=1 low=20.0°C high=none"
+        );
+        assert_eq!(
+            explain_command(&CLONE, &set_unit(&CLONE, TemperatureUnit::Celcius)),
+            "SET_UNIT celsius"
+        );
+    }
+
+    #[test]
+    fn explain_unknown_command() {
+        assert_eq!(
+            explain_command(&STANDARD, &[0x42, 0x01]),
+            "UNKNOWN [42, 01]"
+        );
+    }
+
+    fn assert_close(actual: Range<f32>, expected: Range<f32>) {
+        assert!(
+            (actual.start - expected.start).abs() < 0.001
+                && (actual.end - expected.end).abs() < 0.001,
+            "{:?} is not close to {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn stored_target_range_tenths() {
+        assert_close(
+            stored_target_range(&STANDARD, 20.04..54.56).unwrap(),
+            20.0..54.6,
+        );
+    }
+
+    #[test]
+    fn stored_target_range_whole_degrees() {
+        let profile = DeviceProfile {
+            whole_degree_targets: true,
+            ..STANDARD
+        };
+        assert_close(
+            stored_target_range(&profile, 20.4..54.5).unwrap(),
+            20.0..55.0,
+        );
+    }
+
+    #[test]
+    fn set_unit_standard() {
+        assert_eq!(
+            set_unit(&STANDARD, TemperatureUnit::Celcius),
+            vec![0x02, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            set_unit(&STANDARD, TemperatureUnit::Fahrenheit),
+            vec![0x02, 0x01, 0x00, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn set_unit_clone() {
+        assert_eq!(
+            set_unit(&CLONE, TemperatureUnit::Fahrenheit),
+            vec![0x12, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn enable_real_time_data_standard() {
+        assert_eq!(
+            enable_real_time_data(&STANDARD, true),
+            vec![0x0B, 0x01, 0x00, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            enable_real_time_data(&STANDARD, false),
+            vec![0x0B, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn enable_real_time_data_clone() {
+        assert_eq!(
+            enable_real_time_data(&CLONE, false),
+            vec![0x1B, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn request_property_standard() {
+        assert_eq!(
+            request_property(&STANDARD, 0x42),
+            vec![0x08, 0x42, 0x00, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            request_battery_level(&STANDARD),
+            vec![0x08, 0x24, 0x00, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn request_property_clone() {
+        assert_eq!(
+            request_battery_level(&CLONE),
+            vec![0x18, 0x24, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn silence_alarm_standard() {
+        assert_eq!(
+            silence_alarm(&STANDARD),
+            vec![0x04, 0xFF, 0x00, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn silence_alarm_clone() {
+        assert_eq!(
+            silence_alarm(&CLONE),
+            vec![0x14, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+    }
+}
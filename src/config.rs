@@ -0,0 +1,151 @@
+//! Exporting and importing the client-side configuration of a device, such as a preset for a
+//! particular cook.
+
+use crate::{BBQDevice, Color, Error, ProbeLabels, TemperatureUnit};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+
+/// The configuration of a device which is under the control of this client, from
+/// `BBQDevice::config`.
+///
+/// Unlike a `DeviceSnapshot` this doesn't include any readings, so it can be saved as a preset
+/// and applied to a device later, e.g. the targets and labels for a brisket cook.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct DeviceConfig {
+    /// The target ranges for each probe.
+    pub targets: HashMap<u8, Range<f32>>,
+    /// The labels for the probes.
+    pub labels: ProbeLabels,
+    /// The colors for each probe.
+    pub colors: HashMap<u8, Color>,
+    /// The display unit, if any.
+    pub unit: Option<TemperatureUnit>,
+    /// The calibration offset for probes which don't have their own.
+    pub global_offset: f32,
+    /// The calibration offsets for individual probes.
+    pub probe_offsets: HashMap<u8, f32>,
+}
+
+impl DeviceConfig {
+    /// Write the configuration to the given writer as JSON.
+    pub fn write_to(&self, writer: impl Write) -> Result<(), Error> {
+        Ok(serde_json::to_writer_pretty(writer, self)?)
+    }
+
+    /// Read a configuration previously written by `write_to`.
+    pub fn read_from(reader: impl Read) -> Result<Self, Error> {
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    /// Read a configuration previously saved by `BBQDevice::export_config` from the given file.
+    fn read_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::read_from(BufReader::new(File::open(path)?))
+    }
+}
+
+impl BBQDevice {
+    /// Get the current client-side configuration of the device.
+    pub fn config(&self) -> DeviceConfig {
+        let state = self.state();
+        DeviceConfig {
+            targets: state.targets.clone(),
+            labels: state.labels.clone(),
+            colors: state.colors.clone(),
+            unit: state.unit,
+            global_offset: state.global_offset,
+            probe_offsets: state.probe_offsets.clone(),
+        }
+    }
+
+    /// Apply the given configuration, replacing the labels, colors and offsets and sending the
+    /// display unit and targets to the device. Targets for probes not in the configuration are left
+    /// alone.
+    pub async fn apply_config(&self, config: DeviceConfig) -> Result<(), Error> {
+        {
+            let mut state = self.state();
+            state.labels = config.labels;
+            state.colors = config.colors;
+            state.global_offset = config.global_offset;
+            state.probe_offsets = config.probe_offsets;
+        }
+        if let Some(unit) = config.unit {
+            self.set_temperature_unit(unit).await?;
+        }
+        let mut targets: Vec<_> = config.targets.into_iter().collect();
+        targets.sort_by_key(|(probe, _)| *probe);
+        for (probe, range) in targets {
+            self.set_target_range(probe, range).await?;
+        }
+        Ok(())
+    }
+
+    /// Save the current client-side configuration to the given file as JSON.
+    pub fn export_config(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let file = File::create(path)?;
+        let mut writer = BufWriter::new(file);
+        self.config().write_to(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Load a configuration previously saved by `export_config` from the given file, and apply it
+    /// like `apply_config`.
+    pub async fn import_config(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        self.apply_config(DeviceConfig::read_file(path)?).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut config = DeviceConfig {
+            unit: Some(TemperatureUnit::Fahrenheit),
+            global_offset: -0.5,
+            ..Default::default()
+        };
+        config.targets.insert(0, 90.0..120.0);
+        config.labels.set(1, "brisket");
+        config.colors.insert(1, Color::rgb(0x80, 0x40, 0x00));
+        config.probe_offsets.insert(1, 1.0);
+
+        let mut buffer = Vec::new();
+        config.write_to(&mut buffer).unwrap();
+        assert_eq!(DeviceConfig::read_from(buffer.as_slice()).unwrap(), config);
+    }
+
+    #[test]
+    fn read_partial() {
+        let config = DeviceConfig::read_from(r#"{"global_offset": 1.5}"#.as_bytes()).unwrap();
+        assert_eq!(
+            config,
+            DeviceConfig {
+                global_offset: 1.5,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn read_missing_file() {
+        assert!(matches!(
+            DeviceConfig::read_file("/nonexistent/cloudbbq-config.json"),
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound
+        ));
+    }
+
+    #[test]
+    fn read_invalid_json() {
+        assert!(matches!(
+            DeviceConfig::read_from("not json".as_bytes()),
+            Err(Error::Config(_))
+        ));
+    }
+}
@@ -0,0 +1,325 @@
+//! Waiting for the device to acknowledge setting commands.
+
+use crate::{
+    command_bytes, BBQDevice, BackgroundTask, DeviceState, Error, SettingResult, TemperatureUnit,
+    TARGET_TEMP_NO_MAXIMUM, TARGET_TEMP_NO_MINIMUM,
+};
+use bluez_async::BluetoothError;
+use futures::channel::oneshot;
+use futures::{Future, Stream, StreamExt};
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Matches `SettingResult::AcknowledgeCommand`s from the device to the commands waiting for them.
+///
+/// Acknowledgements only carry the command ID, so if several commands with the same ID are waiting
+/// then they are acknowledged in the order they were sent. The device doesn't acknowledge battery
+/// level requests, so a `SettingResult::BatteryLevel` is treated as their acknowledgement.
+#[derive(Debug, Default)]
+pub(crate) struct AckCorrelator {
+    pending: HashMap<u8, VecDeque<oneshot::Sender<bool>>>,
+    /// The task passing acknowledgements from the device to `acknowledge`, if it has been started.
+    dispatcher: Option<BackgroundTask>,
+}
+
+impl AckCorrelator {
+    /// Register interest in the next acknowledgement for the given command ID, which will be sent
+    /// on the returned receiver.
+    fn register(&mut self, command_id: u8) -> oneshot::Receiver<bool> {
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .entry(command_id)
+            .or_default()
+            .push_back(sender);
+        receiver
+    }
+
+    /// Pass an acknowledgement from the device to the oldest command still waiting for it, if any.
+    fn acknowledge(&mut self, command_id: u8, success: bool) {
+        if let Some(senders) = self.pending.get_mut(&command_id) {
+            // Skip over any commands which have stopped waiting, e.g. because they timed out.
+            while let Some(sender) = senders.pop_front() {
+                if sender.send(success).is_ok() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Cancel all commands still waiting, because acknowledgements are no longer being dispatched.
+    fn stop(&mut self) {
+        self.pending.clear();
+    }
+}
+
+/// Versions of the `BBQDevice` setting commands which wait for the device to acknowledge them,
+/// returned by `BBQDevice::confirmed`.
+///
+/// Each command returns `Error::CommandFailed` if the device acknowledges it as unsuccessful, or
+/// `Error::Timeout` if no acknowledgement arrives within the timeout. Note that a command which
+/// times out has still been written successfully over Bluetooth, and may still take effect.
+#[derive(Clone, Debug)]
+pub struct ConfirmedCommands<'a> {
+    device: &'a BBQDevice,
+    timeout: Duration,
+}
+
+impl BBQDevice {
+    /// Get versions of the setting commands which wait up to the given timeout for the device to
+    /// acknowledge them.
+    pub fn confirmed(&self, timeout: Duration) -> ConfirmedCommands<'_> {
+        ConfirmedCommands {
+            device: self,
+            timeout,
+        }
+    }
+
+    /// Start a background task to pass acknowledgements from the device's setting results to the
+    /// commands waiting for them, if it isn't already running. The task stops when the last clone of
+    /// this device is dropped.
+    async fn start_ack_dispatcher(&self) -> Result<(), BluetoothError> {
+        if BackgroundTask::is_running(&self.state().acks.dispatcher) {
+            return Ok(());
+        }
+        let setting_results = self.setting_results().await?;
+        let mut state = self.state();
+        if BackgroundTask::is_running(&state.acks.dispatcher) {
+            return Ok(());
+        }
+        state.acks.dispatcher = Some(spawn_ack_dispatcher(
+            setting_results,
+            Arc::downgrade(&self.state),
+        ));
+        Ok(())
+    }
+}
+
+impl ConfirmedCommands<'_> {
+    /// Like `BBQDevice::set_temperature_unit`, but waits for the device to acknowledge it.
+    pub async fn set_temperature_unit(&self, unit: TemperatureUnit) -> Result<(), Error> {
+        self.send(command_bytes::set_unit(&self.device.profile(), unit))
+            .await?;
+        self.device.state().unit = Some(unit);
+        Ok(())
+    }
+
+    /// Like `BBQDevice::set_target_range`, but waits for the device to acknowledge it.
+    pub async fn set_target_range(&self, probe: u8, range: Range<f32>) -> Result<(), Error> {
+        self.send(command_bytes::set_target_range(
+            &self.device.profile(),
+            probe,
+            range.clone(),
+        )?)
+        .await?;
+        self.device.state().record_target(probe, range);
+        Ok(())
+    }
+
+    /// Like `BBQDevice::set_target`, but waits for the device to acknowledge it.
+    pub async fn set_target(
+        &self,
+        probe: u8,
+        low: Option<f32>,
+        high: Option<f32>,
+    ) -> Result<(), Error> {
+        self.set_target_range(probe, command_bytes::target_range(low, high))
+            .await
+    }
+
+    /// Like `BBQDevice::set_target_temp`, but waits for the device to acknowledge it.
+    pub async fn set_target_temp(&self, probe: u8, target: f32) -> Result<(), Error> {
+        self.set_target_range(probe, TARGET_TEMP_NO_MINIMUM..target)
+            .await
+    }
+
+    /// Like `BBQDevice::remove_target`, but waits for the device to acknowledge it.
+    pub async fn remove_target(&self, probe: u8) -> Result<(), Error> {
+        self.set_target_range(probe, TARGET_TEMP_NO_MINIMUM..TARGET_TEMP_NO_MAXIMUM)
+            .await
+    }
+
+    /// Like `BBQDevice::enable_real_time_data`, but waits for the device to acknowledge it.
+    pub async fn enable_real_time_data(&self, enable: bool) -> Result<(), Error> {
+        self.send(command_bytes::enable_real_time_data(
+            &self.device.profile(),
+            enable,
+        ))
+        .await?;
+        Ok(())
+    }
+
+    /// Measure the round-trip time to the device, by requesting its battery level and waiting for
+    /// the response. This includes the time taken to write the request over Bluetooth.
+    ///
+    /// This is handy for diagnosing a slow connection, as it doesn't change any settings.
+    pub async fn ping(&self) -> Result<Duration, Error> {
+        self.send(command_bytes::request_battery_level(&self.device.profile()))
+            .await
+    }
+
+    /// Write the given command and wait for it to be acknowledged, returning how long that took.
+    async fn send(&self, command: Vec<u8>) -> Result<Duration, Error> {
+        let command_id = command[0];
+        self.device.start_ack_dispatcher().await?;
+        let ack = self.device.state().acks.register(command_id);
+        round_trip(
+            command_id,
+            self.device.write_setting_unthrottled(command),
+            ack,
+            self.timeout,
+        )
+        .await
+    }
+}
+
+/// Spawn a task to pass acknowledgements from the given setting results to the commands waiting for
+/// them in the given state, until the stream ends or the state is dropped.
+fn spawn_ack_dispatcher(
+    setting_results: impl Stream<Item = SettingResult> + Send + 'static,
+    weak_state: Weak<Mutex<DeviceState>>,
+) -> BackgroundTask {
+    BackgroundTask(tokio::spawn(async move {
+        futures::pin_mut!(setting_results);
+        while let Some(result) = setting_results.next().await {
+            let state = match weak_state.upgrade() {
+                Some(state) => state,
+                None => return,
+            };
+            let mut state = state.lock().unwrap();
+            match result {
+                SettingResult::AcknowledgeCommand {
+                    command_id,
+                    success,
+                    ..
+                } => state.acks.acknowledge(command_id, success),
+                SettingResult::BatteryLevel { .. } => {
+                    let command_id = command_bytes::request_battery_level(&state.profile)[0];
+                    state.acks.acknowledge(command_id, true);
+                }
+                _ => {}
+            }
+        }
+        if let Some(state) = weak_state.upgrade() {
+            state.lock().unwrap().acks.stop();
+        }
+    }))
+}
+
+/// Wait for the given write to finish and then for the given acknowledgement to arrive, returning
+/// how long it took from starting the write.
+async fn round_trip(
+    command_id: u8,
+    write: impl Future<Output = Result<(), BluetoothError>>,
+    ack: oneshot::Receiver<bool>,
+    timeout: Duration,
+) -> Result<Duration, Error> {
+    let start = Instant::now();
+    write.await?;
+    match tokio::time::timeout(timeout, ack).await {
+        Ok(Ok(true)) => Ok(start.elapsed()),
+        Ok(Ok(false)) => Err(Error::CommandFailed(command_id)),
+        Ok(Err(oneshot::Canceled)) => Err(Error::StreamEnded),
+        Err(_) => Err(Error::Timeout),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bluez_async::CharacteristicId;
+    use futures::executor::block_on;
+
+    #[test]
+    fn acknowledge_in_order() {
+        let mut correlator = AckCorrelator::default();
+        let first = correlator.register(0x01);
+        let other = correlator.register(0x02);
+        let second = correlator.register(0x01);
+        correlator.acknowledge(0x01, true);
+        correlator.acknowledge(0x01, false);
+        assert_eq!(block_on(first), Ok(true));
+        assert_eq!(block_on(second), Ok(false));
+        correlator.acknowledge(0x02, true);
+        assert_eq!(block_on(other), Ok(true));
+    }
+
+    #[test]
+    fn acknowledge_skips_abandoned() {
+        let mut correlator = AckCorrelator::default();
+        let abandoned = correlator.register(0x01);
+        let waiting = correlator.register(0x01);
+        drop(abandoned);
+        correlator.acknowledge(0x01, true);
+        assert_eq!(block_on(waiting), Ok(true));
+    }
+
+    #[test]
+    fn acknowledge_unexpected() {
+        let mut correlator = AckCorrelator::default();
+        let waiting = correlator.register(0x01);
+        correlator.acknowledge(0x0B, true);
+        correlator.stop();
+        assert_eq!(block_on(waiting), Err(oneshot::Canceled));
+    }
+
+    #[tokio::test]
+    async fn dispatcher_stops_when_state_dropped() {
+        let characteristic: CharacteristicId = serde_json::from_value(serde_json::json!({
+            "object_path": "/org/bluez/hci0/dev_11_11_11_11_11_11/service0001/char0002"
+        }))
+        .unwrap();
+        let state = Arc::new(Mutex::new(DeviceState::default()));
+        let (sender, events) = futures::channel::mpsc::unbounded();
+        // Build the stream the same way as `BBQDevice::setting_results`, so that any strong
+        // reference it holds to the state would keep the state and so the task alive.
+        let setting_results =
+            crate::setting_results(events, characteristic, Arc::downgrade(&state));
+        state.lock().unwrap().acks.dispatcher = Some(spawn_ack_dispatcher(
+            setting_results,
+            Arc::downgrade(&state),
+        ));
+        tokio::task::yield_now().await;
+        assert!(!sender.is_closed());
+
+        drop(state);
+        // The aborted task drops the stream, and so the receiver, once it is next polled.
+        for _ in 0..10 {
+            if sender.is_closed() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        assert!(sender.is_closed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn round_trip_time() {
+        let (sender, receiver) = oneshot::channel();
+        let write = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            tokio::spawn(async {
+                tokio::time::sleep(Duration::from_millis(400)).await;
+                sender.send(true).unwrap();
+            });
+            Ok(())
+        };
+        assert_eq!(
+            round_trip(0x08, write, receiver, Duration::from_secs(1))
+                .await
+                .unwrap(),
+            Duration::from_millis(500)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn round_trip_timeout() {
+        let (_sender, receiver) = oneshot::channel();
+        assert!(matches!(
+            round_trip(0x08, async { Ok(()) }, receiver, Duration::from_secs(1)).await,
+            Err(Error::Timeout)
+        ));
+    }
+}
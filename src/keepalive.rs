@@ -0,0 +1,106 @@
+//! Keeping the connection to a device alive during long cooks.
+
+use crate::{command_bytes, BBQDevice};
+use futures::Future;
+use log::warn;
+use std::fmt::Display;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{self, MissedTickBehavior};
+
+/// The recommended interval for `BBQDevice::start_keepalive`.
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(20);
+
+/// A handle to a keep-alive task started by `BBQDevice::start_keepalive`. The task is stopped when
+/// this is dropped.
+#[derive(Debug)]
+pub struct KeepAlive {
+    task: JoinHandle<()>,
+}
+
+impl Drop for KeepAlive {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl BBQDevice {
+    /// Start a background task which requests the battery level at the given interval, so the
+    /// connection isn't dropped as idle during a long cook.
+    ///
+    /// `DEFAULT_KEEPALIVE_INTERVAL` of 20 seconds is recommended. If your device still disconnects,
+    /// try a shorter interval; there is little point going much longer, as each request is tiny.
+    ///
+    /// The battery levels will come as `SettingResult` events. The requests are written like any
+    /// other command, so may be delayed slightly by the app's own commands but won't block them for
+    /// long. Failed requests are logged, and the task carries on trying.
+    ///
+    /// This must be called from within a Tokio runtime.
+    pub fn start_keepalive(&self, interval: Duration) -> KeepAlive {
+        let device = self.clone();
+        let task = tokio::spawn(keepalive_loop(interval, move || {
+            let device = device.clone();
+            async move {
+                device
+                    .write_setting_unthrottled(command_bytes::request_battery_level(
+                        &device.profile(),
+                    ))
+                    .await
+            }
+        }));
+        KeepAlive { task }
+    }
+}
+
+/// Call the given function at the given interval forever, starting after the first interval.
+async fn keepalive_loop<F, Fut, E>(interval: Duration, mut send: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+    E: Display,
+{
+    let mut ticks = time::interval_at(time::Instant::now() + interval, interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        if let Err(e) = send().await {
+            warn!("Failed to send keep-alive: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test(start_paused = true)]
+    async fn keepalive_interval() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let start = time::Instant::now();
+        let task = tokio::spawn(keepalive_loop(Duration::from_secs(20), {
+            let sent = sent.clone();
+            move || {
+                let mut sent = sent.lock().unwrap();
+                sent.push(start.elapsed());
+                // Failures shouldn't stop the keep-alive.
+                future::ready(if sent.len() == 1 {
+                    Err("failed")
+                } else {
+                    Ok(())
+                })
+            }
+        }));
+        time::sleep(Duration::from_secs(65)).await;
+        task.abort();
+        assert_eq!(
+            *sent.lock().unwrap(),
+            vec![
+                Duration::from_secs(20),
+                Duration::from_secs(40),
+                Duration::from_secs(60)
+            ]
+        );
+    }
+}
@@ -1,15 +1,57 @@
 use bluez_async::{
-    uuid_from_u16, BluetoothError, BluetoothEvent, BluetoothSession, CharacteristicEvent,
-    CharacteristicId, DeviceId, DeviceInfo,
+    uuid_from_u16, AdapterId, AdapterInfo, BleUuid, BluetoothError, BluetoothEvent,
+    BluetoothSession, CharacteristicEvent, CharacteristicFlags, CharacteristicId,
+    CharacteristicInfo, DeviceEvent, DeviceId, DeviceInfo,
 };
-use futures::future;
-use futures::stream::{Stream, StreamExt};
-use log::info;
-use std::convert::TryInto;
+use futures::future::{self, Either};
+use futures::stream::{self, Stream, StreamExt};
+use log::{debug, info, warn};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::{TryFrom, TryInto};
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::io::{self, Write};
 use std::ops::Range;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime};
 use thiserror::Error;
+use tokio::task::JoinHandle;
 use uuid::Uuid;
 
+mod adapters;
+#[cfg(feature = "blocking")]
+mod blocking;
+pub mod command_bytes;
+#[cfg(feature = "serde")]
+mod config;
+mod confirm;
+mod keepalive;
+mod profile;
+#[cfg(feature = "replay")]
+mod replay;
+#[cfg(test)]
+mod replay_tests;
+mod session;
+mod stats;
+
+pub use adapters::RealTimeStreamExt;
+#[cfg(feature = "blocking")]
+pub use blocking::BlockingBBQDevice;
+#[cfg(feature = "serde")]
+pub use config::DeviceConfig;
+pub use confirm::ConfirmedCommands;
+pub use keepalive::{KeepAlive, DEFAULT_KEEPALIVE_INTERVAL};
+pub use profile::{ByteOrder, DeviceProfile, KNOWN_PROFILES};
+#[cfg(feature = "replay")]
+pub use replay::{ParseFixtureError, ReplayDevice, ReplayEvent};
+pub use session::{
+    SessionEvent, SessionManager, SessionOptions, SessionState, DEFAULT_EVENT_BUFFER_SIZE,
+};
+pub use stats::{CookStats, ProbeStats};
+
 // https://gist.github.com/uucidl/b9c60b6d36d8080d085a8e3310621d64
 const BBQ_SERVICE_UUID: Uuid = uuid_from_u16(0xFFF0);
 const SETTING_RESULT_UUID: Uuid = uuid_from_u16(0xFFF1);
@@ -18,10 +60,21 @@ const HISTORY_DATA_UUID: Uuid = uuid_from_u16(0xFFF3);
 const REAL_TIME_DATA_UUID: Uuid = uuid_from_u16(0xFFF4);
 const SETTING_DATA_UUID: Uuid = uuid_from_u16(0xFFF5);
 
-const CREDENTIAL_MSG: [u8; 15] = [
+/// The credential which the official app sends to authenticate with the device, as captured in the
+/// protocol spec linked above. This works for all devices known so far.
+pub const DEFAULT_CREDENTIAL: &[u8] = &[
     0x21, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01, 0xb8, 0x22, 0x00, 0x00, 0x00, 0x00, 0x00,
 ];
 
+/// Credentials known to work with particular devices, keyed by a prefix of the name which the device
+/// advertises, matched as for `DeviceProfile::for_name`. Devices not matching any of these are sent
+/// `DEFAULT_CREDENTIAL`.
+///
+/// If your device needs a different credential, capture what the official app writes to the
+/// 'account and verify' characteristic and pass it to `BBQDevice::authenticate_with`.
+pub const KNOWN_CREDENTIALS: &[(&str, &[u8])] =
+    &[("BBQ", DEFAULT_CREDENTIAL), ("iBBQ", DEFAULT_CREDENTIAL)];
+
 // Possible values for the first byte of 'setting data'.
 const SET_TARGET_TEMP_COMMAND: u8 = 0x01;
 const SET_UNIT_COMMAND: u8 = 0x02;
@@ -38,6 +91,37 @@ const SILENCE_ARGUMENT: u8 = 0xff;
 const BATTERY_LEVEL_PROPERTY_ID: u8 = 0x24;
 const ACKNOWLEDGE_COMMAND: u8 = 0xFF;
 
+/// The kinds of 'setting result' frame, identified by their first byte.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+enum SettingResultId {
+    /// An acknowledgement that a command has been received.
+    Acknowledge = ACKNOWLEDGE_COMMAND,
+    /// A response to a request for the battery level property.
+    BatteryLevel = BATTERY_LEVEL_PROPERTY_ID,
+    /// A notification that the alarm was silenced. This uses the same ID as the command to silence
+    /// the alarm.
+    SilencePressed = SILENCE_COMMAND,
+}
+
+impl TryFrom<u8> for SettingResultId {
+    type Error = u8;
+
+    fn try_from(id: u8) -> Result<Self, Self::Error> {
+        match id {
+            ACKNOWLEDGE_COMMAND => Ok(SettingResultId::Acknowledge),
+            BATTERY_LEVEL_PROPERTY_ID => Ok(SettingResultId::BatteryLevel),
+            SILENCE_COMMAND => Ok(SettingResultId::SilencePressed),
+            _ => Err(id),
+        }
+    }
+}
+
+/// How many real-time data frames `BBQDevice::detect_byte_order` looks at.
+const BYTE_ORDER_DETECTION_FRAMES: usize = 5;
+/// The range of temperatures which `BBQDevice::detect_byte_order` considers plausible readings.
+const PLAUSIBLE_TEMPERATURE_RANGE: Range<f32> = -50.0..1000.0;
+
 // Special temperature values.
 /// This temperature reading value indicates that there is no probe connected.
 const ABSENT_PROBE_VALUE: f32 = -1.0;
@@ -52,7 +136,17 @@ const TEMPERATURE_MAX: f32 = i16::MAX as f32 / 10.0;
 /// The minimum temperature which can be encoded in the fixed-point format used by the device.
 const TEMPERATURE_MIN: f32 = i16::MIN as f32 / 10.0;
 
-const DEVICE_NAMES: [&str; 2] = ["BBQ", "iBBQ"];
+/// How long `BBQDevice::find_first` waits for service discovery after connecting to a device.
+const SERVICES_RESOLVED_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often to check whether service discovery has finished.
+const SERVICES_RESOLVED_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long `BBQDevice::handshake` waits for the device to report its properties.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The Bluetooth device names advertised by compatible BBQ thermometers, as matched by
+/// `is_compatible_name`.
+pub const DEVICE_NAMES: &[&str] = &["BBQ", "iBBQ"];
 
 /// An error communicating with a BBQ thermometer device.
 #[derive(Debug, Error)]
@@ -63,32 +157,605 @@ pub enum Error {
     /// There was an error communicating over Bluetooth.
     #[error(transparent)]
     Bluetooth(#[from] BluetoothError),
+    /// The given probe is not connected to the device.
+    #[error("Probe {0} is not connected")]
+    ProbeDisconnected(u8),
+    /// The operation did not complete in time.
+    #[error("Timed out")]
+    Timeout,
+    /// The device acknowledged the command with the given ID as unsuccessful.
+    #[error("Command {0:#04x} failed")]
+    CommandFailed(u8),
+    /// A target was set on the given probe, but the device doesn't have that many probes.
+    #[error("Probe {probe} doesn't exist, the device only has {probe_count} probes")]
+    NoSuchProbe { probe: u8, probe_count: usize },
+    /// The given target range has its lower bound above its upper bound, and neither is the value
+    /// meaning no alarm on that side.
+    #[error("Target range {0:?} has its low alarm above its high alarm")]
+    InvertedTargetRange(Range<f32>),
+    /// The device is not currently connected.
+    #[error("Not connected")]
+    NotConnected,
+    /// The device profile has no command to do the given operation.
+    #[error("The device profile has no command to {0}")]
+    UnsupportedCommand(&'static str),
+    /// No compatible device was found.
+    #[error("No compatible device found")]
+    NoDeviceFound,
+    /// The stream of data from the device ended unexpectedly.
+    #[error("Stream of data from the device ended")]
+    StreamEnded,
+    /// The runtime for `BlockingBBQDevice` could not be started.
+    #[cfg(feature = "blocking")]
+    #[error("Failed to start runtime: {0}")]
+    Runtime(std::io::Error),
+    /// An exported configuration couldn't be serialized, or parsed when importing it.
+    #[cfg(feature = "serde")]
+    #[error("Error serializing or parsing config: {0}")]
+    Config(#[from] serde_json::Error),
+    /// There was an error reading or writing the file for an exported configuration.
+    #[cfg(feature = "serde")]
+    #[error("Error reading or writing config file: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// An error parsing a `TemperatureUnit` from a string.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+#[error("Unknown temperature unit {0:?}, expected \"celsius\" or \"fahrenheit\"")]
+pub struct ParseTemperatureUnitError(String);
+
+/// List the characteristics of the given device's BBQ service, with their UUIDs.
+///
+/// Unlike `BBQDevice::new` this doesn't require the device to have all the expected
+/// characteristics, so it can be used to find out the GATT layout of an unsupported clone for a
+/// compatibility report. The device must already be connected.
+pub async fn dump_gatt(
+    bt_session: &BluetoothSession,
+    device: &DeviceId,
+) -> Result<Vec<(Uuid, CharacteristicInfo)>, BluetoothError> {
+    let service = bt_session
+        .get_service_by_uuid(device, BBQ_SERVICE_UUID)
+        .await?;
+    Ok(bt_session
+        .get_characteristics(&service.id)
+        .await?
+        .into_iter()
+        .map(|characteristic| (characteristic.uuid, characteristic))
+        .collect())
 }
 
 /// Return all compatible BBQ thermometer devices currently known by the system.
 pub async fn find_devices(bt_session: &BluetoothSession) -> Result<Vec<DeviceInfo>, Error> {
+    find_devices_with_options(bt_session, &DiscoveryOptions::default()).await
+}
+
+/// Return all devices currently known by the system which match the given predicate, e.g. for
+/// rebadged thermometers whose names aren't recognised by `is_compatible_name`.
+pub async fn find_devices_matching(
+    bt_session: &BluetoothSession,
+    predicate: impl Fn(&DeviceInfo) -> bool,
+) -> Result<Vec<DeviceInfo>, Error> {
     let devices = bt_session.get_devices().await?;
     Ok(devices
         .into_iter()
-        .filter(BBQDevice::is_compatible)
+        .filter(|device| predicate(device))
         .collect())
 }
 
+/// Return whether the given advertised name is that of a compatible BBQ thermometer, i.e. whether
+/// it starts with one of `DEVICE_NAMES`, ignoring case. Some devices add a suffix to the name, such
+/// as `BBQ_D`.
+pub fn is_compatible_name(name: &str) -> bool {
+    DEVICE_NAMES
+        .iter()
+        .any(|pattern| name_matches(name, pattern))
+}
+
+/// Return whether the given name starts with the given pattern, ignoring case.
+fn name_matches(name: &str, pattern: &str) -> bool {
+    name.to_lowercase().starts_with(&pattern.to_lowercase())
+}
+
+/// Find the entry in the given table whose name matches the given device name as for
+/// `is_compatible_name`, preferring the longest if several match.
+pub(crate) fn lookup_by_name<'a, T>(table: &'a [(&str, T)], name: Option<&str>) -> Option<&'a T> {
+    let name = name?;
+    table
+        .iter()
+        .filter(|(pattern, _)| name_matches(name, pattern))
+        .max_by_key(|(pattern, _)| pattern.len())
+        .map(|(_, value)| value)
+}
+
+/// Return compatible BBQ thermometer devices currently known by the system, filtered according to
+/// the given options.
+pub async fn find_devices_with_options(
+    bt_session: &BluetoothSession,
+    options: &DiscoveryOptions,
+) -> Result<Vec<DeviceInfo>, Error> {
+    let devices = bt_session.get_devices().await?;
+    Ok(filter_devices(devices, options))
+}
+
+/// Scan for the given duration, then stop scanning and return all compatible BBQ thermometer
+/// devices found.
+///
+/// This does the whole of the usual discovery sequence in one call: starting discovery, waiting,
+/// stopping discovery and then calling `find_devices`. Discovery is always stopped afterwards, even
+/// if finding devices fails or the returned future is dropped before it completes.
+pub async fn scan(
+    bt_session: &BluetoothSession,
+    duration: Duration,
+) -> Result<Vec<DeviceInfo>, Error> {
+    scan_adapter(bt_session, None, duration).await
+}
+
+/// Like `scan`, but only scans on the given Bluetooth adapter, and only returns devices found by
+/// it.
+///
+/// This is useful on hosts with several adapters, e.g. to use a dedicated long-range dongle. Use
+/// `list_adapters` to find the adapter you want, e.g. by its `mac_address`, or by its `id` which
+/// ends with the adapter's name such as `hci1`.
+pub async fn scan_on_adapter(
+    bt_session: &BluetoothSession,
+    adapter: &AdapterId,
+    duration: Duration,
+) -> Result<Vec<DeviceInfo>, Error> {
+    scan_adapter(bt_session, Some(adapter), duration).await
+}
+
+/// List the Bluetooth adapters available on the system, for use with `scan_on_adapter` and
+/// `BBQDevice::find_first_on_adapter`.
+pub async fn list_adapters(
+    bt_session: &BluetoothSession,
+) -> Result<Vec<AdapterInfo>, BluetoothError> {
+    bt_session.get_adapters().await
+}
+
+/// Scan on the given adapter, or all adapters if it is `None`.
+async fn scan_adapter(
+    bt_session: &BluetoothSession,
+    adapter: Option<&AdapterId>,
+    duration: Duration,
+) -> Result<Vec<DeviceInfo>, Error> {
+    match adapter {
+        Some(adapter) => bt_session.start_discovery_on_adapter(adapter).await?,
+        None => bt_session.start_discovery().await?,
+    }
+    let mut guard = DiscoveryGuard(Some((bt_session.clone(), adapter.cloned())));
+    tokio::time::sleep(duration).await;
+    let devices = match adapter {
+        Some(adapter) => bt_session.get_devices_on_adapter(adapter).await,
+        None => bt_session.get_devices().await,
+    };
+    guard.0 = None;
+    stop_discovery(bt_session, adapter).await?;
+    Ok(filter_devices(devices?, &DiscoveryOptions::default()))
+}
+
+async fn stop_discovery(
+    bt_session: &BluetoothSession,
+    adapter: Option<&AdapterId>,
+) -> Result<(), BluetoothError> {
+    match adapter {
+        Some(adapter) => bt_session.stop_discovery_on_adapter(adapter).await,
+        None => bt_session.stop_discovery().await,
+    }
+}
+
+/// Stops discovery on the given session and adapter when dropped, unless it has been taken.
+struct DiscoveryGuard(Option<(BluetoothSession, Option<AdapterId>)>);
+
+impl Drop for DiscoveryGuard {
+    fn drop(&mut self) {
+        if let Some((bt_session, adapter)) = self.0.take() {
+            // The guard may be dropped outside of a runtime, e.g. when the runtime is shutting down,
+            // in which case there is no way to stop discovery.
+            match tokio::runtime::Handle::try_current() {
+                Ok(handle) => {
+                    handle.spawn(async move {
+                        if let Err(e) = stop_discovery(&bt_session, adapter.as_ref()).await {
+                            warn!("Failed to stop discovery: {}", e);
+                        }
+                    });
+                }
+                Err(_) => warn!("Not stopping discovery as there is no runtime to do it on"),
+            }
+        }
+    }
+}
+
+/// Options for which devices `find_devices_with_options` should return.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DiscoveryOptions {
+    /// Only return the first device with any given MAC address. This is useful when the same device
+    /// has been seen by more than one Bluetooth adapter.
+    pub dedupe_by_address: bool,
+    /// Return at most this many devices.
+    pub max_results: Option<usize>,
+    /// Also return devices with no name which advertise the BBQ service UUID. Some devices only
+    /// advertise their name in the scan response, so it may be missing during passive scanning.
+    pub match_service_uuid: bool,
+    /// Also return devices whose names start with any of these, ignoring case, for rebadged clones
+    /// which advertise a name not in `DEVICE_NAMES`.
+    pub extra_names: Vec<String>,
+}
+
+/// Return a short human-readable summary of the given device, including its MAC address so that
+/// several devices with the same name can be told apart.
+pub fn device_summary(device: &DeviceInfo) -> String {
+    format!(
+        "{} ({}, RSSI {})",
+        device.name.as_deref().unwrap_or("unnamed"),
+        device.mac_address,
+        device
+            .rssi
+            .map_or_else(|| "unknown".to_owned(), |rssi| rssi.to_string())
+    )
+}
+
+/// Pair each of the given history records, oldest first, with an estimate of the time at which it
+/// was recorded.
+///
+/// History records don't carry timestamps, so this assumes that they were recorded at exactly the
+/// given interval with the newest one at `end_time` (e.g. when the history was downloaded), and
+/// works backwards from there. Gaps in recording, such as while the device was off, will make the
+/// older times wrong. Any records whose estimated time would be too far before `end_time` to be
+/// represented are skipped.
+pub fn with_reconstructed_times<T>(
+    records: Vec<T>,
+    interval: Duration,
+    end_time: SystemTime,
+) -> Vec<(SystemTime, T)> {
+    let count = records.len();
+    records
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, record)| {
+            let age = interval.checked_mul(u32::try_from(count - 1 - index).ok()?)?;
+            Some((end_time.checked_sub(age)?, record))
+        })
+        .collect()
+}
+
+/// Sort the given timestamped history records by time, and remove any duplicates with the same
+/// timestamp, e.g. from records which were retransmitted or downloaded twice. The first of each set
+/// of duplicates is kept.
+///
+/// History records carry no sequence number, so timestamps are the only way to detect duplicates.
+pub fn dedup_history<T>(mut records: Vec<(SystemTime, T)>) -> Vec<(SystemTime, T)> {
+    // The sort is stable, so the first of each set of duplicates stays first.
+    records.sort_by_key(|(time, _)| *time);
+    records.dedup_by_key(|(time, _)| *time);
+    records
+}
+
+/// Write the given timestamped history records as CSV, with a header row and then a row for each
+/// record giving its time in seconds since the Unix epoch and the temperature of each probe in
+/// degrees Celsius, e.g. `1700000000,51.3,,22`.
+///
+/// There is a column for every probe in the record with the most probes. Probes which were
+/// disconnected are left empty, and times before the Unix epoch are written as 0.
+pub fn write_history_csv(
+    mut writer: impl Write,
+    records: &[(SystemTime, HistoryData)],
+) -> io::Result<()> {
+    let probe_count = records
+        .iter()
+        .map(|(_, record)| record.probe_temperatures.len())
+        .max()
+        .unwrap_or(0);
+    write!(writer, "time")?;
+    for probe in 0..probe_count {
+        write!(writer, ",probe{}", probe)?;
+    }
+    writeln!(writer)?;
+    for (time, record) in records {
+        let seconds = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        write!(writer, "{}", seconds)?;
+        for probe in 0..probe_count {
+            write!(writer, ",")?;
+            if let Some(Some(temperature)) = record.probe_temperatures.get(probe) {
+                write!(writer, "{}", temperature)?;
+            }
+        }
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+/// Wait until BlueZ has finished discovering the GATT services of the given device, which happens
+/// some time after connecting to it.
+async fn wait_for_services_resolved(
+    bt_session: &BluetoothSession,
+    device: &DeviceId,
+) -> Result<(), Error> {
+    tokio::time::timeout(SERVICES_RESOLVED_TIMEOUT, async {
+        while !bt_session.get_device_info(device).await?.services_resolved {
+            tokio::time::sleep(SERVICES_RESOLVED_POLL_INTERVAL).await;
+        }
+        Ok(())
+    })
+    .await
+    .unwrap_or(Err(Error::Timeout))
+}
+
+/// Return the credential to authenticate with a device with the given name.
+fn credential_for_name(name: Option<&str>) -> &'static [u8] {
+    lookup_by_name(KNOWN_CREDENTIALS, name).map_or(DEFAULT_CREDENTIAL, |credential| credential)
+}
+
+fn filter_devices(devices: Vec<DeviceInfo>, options: &DiscoveryOptions) -> Vec<DeviceInfo> {
+    let mut seen_addresses = HashSet::new();
+    devices
+        .into_iter()
+        .filter(|device| {
+            BBQDevice::is_compatible(device)
+                || matches!(&device.name, Some(name) if options
+                    .extra_names
+                    .iter()
+                    .any(|pattern| name_matches(name, pattern)))
+                || (options.match_service_uuid
+                    && device.name.is_none()
+                    && device.services.contains(&BBQ_SERVICE_UUID))
+        })
+        .filter(|device| !options.dedupe_by_address || seen_addresses.insert(device.mac_address))
+        .take(options.max_results.unwrap_or(usize::MAX))
+        .collect()
+}
+
 /// A Bluetooth BBQ thermometer device which is connected.
+///
+/// Clones share the same connection and client-side state. Setting commands are serialized per
+/// device, so commands issued concurrently from several clones are written one at a time rather
+/// than interleaved.
 #[derive(Clone, Debug)]
 pub struct BBQDevice {
     bt_session: BluetoothSession,
+    name: Option<String>,
     setting_result_characteristic: CharacteristicId,
     account_and_verify_characteristic: CharacteristicId,
     history_data_characteristic: CharacteristicId,
     real_time_data_characteristic: CharacteristicId,
     setting_data_characteristic: CharacteristicId,
+    state: Arc<Mutex<DeviceState>>,
+    /// Held while writing to the setting data characteristic, to serialize commands.
+    write_lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+/// Client-side state associated with a device, shared between all clones of a `BBQDevice`.
+#[derive(Debug, Default)]
+struct DeviceState {
+    acks: confirm::AckCorrelator,
+    /// Whether this client has sent the credential to the device since it last connected or
+    /// disconnected.
+    authenticated: bool,
+    /// The task which resets `authenticated` when the device connects or disconnects, if it has
+    /// been started.
+    connection_watcher: Option<BackgroundTask>,
+    profile: DeviceProfile,
+    labels: ProbeLabels,
+    /// The colors which have been set for each probe.
+    colors: HashMap<u8, Color>,
+    /// The calibration offset added to probes which don't have their own.
+    global_offset: f32,
+    /// The calibration offsets which have been set for individual probes.
+    probe_offsets: HashMap<u8, f32>,
+    /// The most recent reading received from the device.
+    last_reading: Option<RealTimeData>,
+    /// When the most recent reading was received.
+    last_reading_time: Option<Instant>,
+    /// The most recent readings and when they were received, oldest first, if the history buffer is
+    /// enabled.
+    recent_readings: VecDeque<(SystemTime, RealTimeData)>,
+    /// The maximum number of readings to keep in `recent_readings`, or 0 if it is disabled.
+    recent_readings_capacity: usize,
+    /// The characteristics on which this client has started notifications.
+    notifying: HashSet<CharacteristicId>,
+    real_time: RealTimeState,
+    /// The target ranges which have been set for each probe.
+    targets: HashMap<u8, Range<f32>>,
+    /// The safety ceilings which have been set for each probe.
+    safety_ceilings: HashMap<u8, f32>,
+    /// The display unit which has been set.
+    unit: Option<TemperatureUnit>,
+    /// Configuration for dropping repeated commands, if any.
+    throttle: Option<CommandThrottle>,
+    /// The last setting command written, and when.
+    last_command: Option<(Vec<u8>, Instant)>,
+    /// Whether to log every raw frame received.
+    log_raw_frames: bool,
+}
+
+/// A background task which holds a weak reference to a `DeviceState`, and so is aborted when it is
+/// dropped along with the state.
+#[derive(Debug)]
+struct BackgroundTask(JoinHandle<()>);
+
+impl BackgroundTask {
+    /// Return whether the given task has been started and is still running.
+    fn is_running(task: &Option<BackgroundTask>) -> bool {
+        matches!(task, Some(task) if !task.0.is_finished())
+    }
+}
+
+impl Drop for BackgroundTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl DeviceState {
+    /// Record that the given reading was received at the given time.
+    fn record_reading(&mut self, reading: RealTimeData, time: Instant) {
+        self.last_reading = Some(reading);
+        self.last_reading_time = Some(time);
+    }
+
+    /// Add the given reading to the history buffer, dropping the oldest if it is full. Does nothing
+    /// if the buffer is disabled.
+    fn buffer_reading(&mut self, time: SystemTime, reading: RealTimeData) {
+        if self.recent_readings_capacity == 0 {
+            return;
+        }
+        if self.recent_readings.len() == self.recent_readings_capacity {
+            self.recent_readings.pop_front();
+        }
+        self.recent_readings.push_back((time, reading));
+    }
+
+    /// Change the capacity of the history buffer, keeping the most recent readings which fit.
+    fn set_recent_readings_capacity(&mut self, capacity: usize) {
+        self.recent_readings_capacity = capacity;
+        let excess = self.recent_readings.len().saturating_sub(capacity);
+        self.recent_readings.drain(..excess);
+        self.recent_readings.shrink_to_fit();
+    }
+
+    /// Return how long before the given time the most recent reading was received, if any.
+    fn last_reading_age(&self, now: Instant) -> Option<Duration> {
+        self.last_reading_time
+            .map(|time| now.saturating_duration_since(time))
+    }
+
+    /// Return the calibration offset for the given probe: its own offset if it has one, or else the
+    /// global offset.
+    fn offset(&self, probe: u8) -> f32 {
+        self.probe_offsets
+            .get(&probe)
+            .copied()
+            .unwrap_or(self.global_offset)
+    }
+
+    /// Add the calibration offset for each probe to its temperature in the given reading.
+    fn apply_offsets(&self, reading: RealTimeData) -> RealTimeData {
+        RealTimeData {
+            probe_temperatures: reading
+                .probe_temperatures
+                .into_iter()
+                .enumerate()
+                .map(|(index, temperature)| {
+                    let offset =
+                        u8::try_from(index).map_or(self.global_offset, |probe| self.offset(probe));
+                    temperature.map(|t| t + offset)
+                })
+                .collect(),
+        }
+    }
+
+    /// Set or remove the color for the given probe.
+    fn set_color(&mut self, probe: u8, color: Option<Color>) {
+        match color {
+            Some(color) => self.colors.insert(probe, color),
+            None => self.colors.remove(&probe),
+        };
+    }
+
+    /// Return whether the given command should be written now, according to the command throttle.
+    fn should_write(&self, command: &[u8], now: Instant) -> bool {
+        if let (Some(throttle), Some((last_command, last_time))) =
+            (&self.throttle, &self.last_command)
+        {
+            if last_command == command
+                && now.saturating_duration_since(*last_time) < throttle.window
+            {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Record that the given command was successfully written at the given time, for the command
+    /// throttle.
+    fn record_command(&mut self, command: Vec<u8>, now: Instant) {
+        self.last_command = Some((command, now));
+    }
+
+    /// Record that the given target range has been set for the given probe.
+    fn record_target(&mut self, probe: u8, range: Range<f32>) {
+        if range == (TARGET_TEMP_NO_MINIMUM..TARGET_TEMP_NO_MAXIMUM) {
+            self.targets.remove(&probe);
+        } else {
+            self.targets.insert(probe, range);
+        }
+    }
+
+    /// Record that real-time data has been paused, returning whether it was previously enabled and
+    /// so needs to be disabled on the device.
+    fn pause_real_time(&mut self) -> bool {
+        let enabled = self.real_time == RealTimeState::Enabled;
+        if enabled {
+            self.real_time = RealTimeState::Paused;
+        }
+        enabled
+    }
+
+    /// Record that real-time data has been resumed, returning whether it was previously paused and
+    /// so needs to be enabled on the device.
+    fn resume_real_time(&mut self) -> bool {
+        let paused = self.real_time == RealTimeState::Paused;
+        if paused {
+            self.real_time = RealTimeState::Enabled;
+        }
+        paused
+    }
+}
+
+/// Configuration for dropping repeated setting commands, set with
+/// `BBQDevice::set_command_throttle`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CommandThrottle {
+    /// A command identical to the last one written is dropped if it is within this long of it.
+    pub window: Duration,
+}
+
+/// Whether this client has asked the device to send real-time data.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+enum RealTimeState {
+    #[default]
+    Disabled,
+    Enabled,
+    Paused,
 }
 
 impl BBQDevice {
-    /// Return whether the given Bluetooth device is a compatible BBQ thermometer.
+    /// Return whether the given Bluetooth device is a compatible BBQ thermometer, i.e. whether its
+    /// name matches `is_compatible_name`.
     pub fn is_compatible(device: &DeviceInfo) -> bool {
-        matches!(&device.name, Some(name) if DEVICE_NAMES.contains(&name.as_str()))
+        matches!(&device.name, Some(name) if is_compatible_name(name))
+    }
+
+    /// Scan for the given duration, then connect to and authenticate with the first compatible
+    /// device found.
+    ///
+    /// This is a convenience for simple scripts which only have one thermometer. Use
+    /// `find_devices` and `BBQDevice::new` for more control.
+    pub async fn find_first(scan_duration: Duration) -> Result<BBQDevice, Error> {
+        Self::find_first_impl(None, scan_duration).await
+    }
+
+    /// Like `find_first`, but only scans on the given Bluetooth adapter. See `scan_on_adapter`.
+    pub async fn find_first_on_adapter(
+        adapter: &AdapterId,
+        scan_duration: Duration,
+    ) -> Result<BBQDevice, Error> {
+        Self::find_first_impl(Some(adapter), scan_duration).await
+    }
+
+    async fn find_first_impl(
+        adapter: Option<&AdapterId>,
+        scan_duration: Duration,
+    ) -> Result<BBQDevice, Error> {
+        let (_, bt_session) = BluetoothSession::new().await?;
+        let devices = scan_adapter(&bt_session, adapter, scan_duration).await?;
+        let device = devices.into_iter().next().ok_or(Error::NoDeviceFound)?;
+        bt_session.connect(&device.id).await?;
+        wait_for_services_resolved(&bt_session, &device.id).await?;
+        let device = BBQDevice::new(bt_session, device.id).await?;
+        device.authenticate().await?;
+        Ok(device)
     }
 
     /// Construct a new BBQDevice wrapper around an appropriate Bluetooth device which is already
@@ -97,6 +764,8 @@ impl BBQDevice {
         bt_session: BluetoothSession,
         device: DeviceId,
     ) -> Result<BBQDevice, BluetoothError> {
+        let name = bt_session.get_device_info(&device).await?.name;
+        let profile = DeviceProfile::for_name(name.as_deref());
         let service = bt_session
             .get_service_by_uuid(&device, BBQ_SERVICE_UUID)
             .await?
@@ -123,54 +792,191 @@ impl BBQDevice {
             .id;
         Ok(BBQDevice {
             bt_session,
+            name,
             setting_result_characteristic,
             account_and_verify_characteristic,
             history_data_characteristic,
             real_time_data_characteristic,
             setting_data_characteristic,
+            state: Arc::new(Mutex::new(DeviceState {
+                profile,
+                ..Default::default()
+            })),
+            write_lock: Default::default(),
+        })
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, DeviceState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Get the profile used to encode commands for the device.
+    pub fn profile(&self) -> DeviceProfile {
+        self.state().profile.clone()
+    }
+
+    /// Change the profile used to encode commands for the device. By default this is chosen from
+    /// `KNOWN_PROFILES` based on the device's name.
+    pub fn set_profile(&self, profile: DeviceProfile) {
+        self.state().profile = profile;
+    }
+
+    fn device_id(&self) -> DeviceId {
+        self.setting_data_characteristic.service().device()
+    }
+
+    /// Set whether and how identical consecutive setting commands should be dropped, e.g. to avoid
+    /// flooding the device with the same target while a UI slider is being dragged. By default no
+    /// commands are dropped.
+    ///
+    /// A command which is dropped still returns `Ok`. Commands sent through `confirmed` are never
+    /// dropped, as they would then never be acknowledged.
+    pub fn set_command_throttle(&self, throttle: Option<CommandThrottle>) {
+        self.state().throttle = throttle;
+    }
+
+    /// Write the given command to the setting data characteristic, unless it is dropped by the
+    /// command throttle.
+    async fn write_setting(&self, command: Vec<u8>) -> Result<(), BluetoothError> {
+        self.write_setting_then(command, |_| {}).await
+    }
+
+    /// Write the given command like `write_setting`, then apply `record` to the device state if it
+    /// succeeds. The write lock is held until `record` has run, so that the state updates of
+    /// concurrent callers are applied in the same order as their writes.
+    async fn write_setting_then(
+        &self,
+        command: Vec<u8>,
+        record: impl FnOnce(&mut DeviceState),
+    ) -> Result<(), BluetoothError> {
+        serialized_write(&self.write_lock, &self.state, command, record, |command| {
+            self.bt_session
+                .write_characteristic_value(&self.setting_data_characteristic, command)
         })
+        .await
+    }
+
+    async fn write_setting_unthrottled(&self, command: Vec<u8>) -> Result<(), BluetoothError> {
+        let _guard = self.write_lock.lock().await;
+        self.bt_session
+            .write_characteristic_value(&self.setting_data_characteristic, command)
+            .await
     }
 
     /// Authenticate with the device. This must be done before anything else, or it will disconnect
     /// after a short time.
+    ///
+    /// The credential sent is chosen from `KNOWN_CREDENTIALS` based on the device's name.
     pub async fn authenticate(&self) -> Result<(), BluetoothError> {
-        self.bt_session
-            .write_characteristic_value(&self.account_and_verify_characteristic, CREDENTIAL_MSG)
+        self.authenticate_with(credential_for_name(self.name.as_deref()))
             .await
     }
 
+    /// Authenticate with the device using the given credential, for devices which need a different
+    /// one to those in `KNOWN_CREDENTIALS`.
+    pub async fn authenticate_with(&self, credential: &[u8]) -> Result<(), BluetoothError> {
+        self.start_connection_watcher().await?;
+        self.bt_session
+            .write_characteristic_value(&self.account_and_verify_characteristic, credential)
+            .await?;
+        self.state().authenticated = true;
+        Ok(())
+    }
+
+    /// Enable or disable logging every raw frame received on the real-time data and setting result
+    /// characteristics, at `debug` level with a hex dump, before it is parsed.
+    ///
+    /// This is intended for capturing frames from unsupported devices to attach to bug reports. It
+    /// only applies to streams from `real_time`, `setting_results` and the methods built on them.
+    pub fn log_raw_frames(&self, enable: bool) {
+        self.state().log_raw_frames = enable;
+    }
+
+    /// Return whether `authenticate` needs to be called, because it hasn't been called yet or the
+    /// device has connected or disconnected since it was.
+    ///
+    /// Until it is called again, readings from `real_time` are discarded as for a new connection.
+    pub fn needs_reauth(&self) -> bool {
+        !self.state().authenticated
+    }
+
+    /// Start a background task to reset the authentication state whenever the device connects or
+    /// disconnects, if it isn't already running. The task stops when the last clone of this device
+    /// is dropped.
+    async fn start_connection_watcher(&self) -> Result<(), BluetoothError> {
+        if BackgroundTask::is_running(&self.state().connection_watcher) {
+            return Ok(());
+        }
+        let device_id = self.device_id();
+        let events = self.bt_session.device_event_stream(&device_id).await?;
+        let mut state = self.state();
+        if !BackgroundTask::is_running(&state.connection_watcher) {
+            state.connection_watcher = Some(BackgroundTask(tokio::spawn(track_connection(
+                events,
+                device_id,
+                Arc::downgrade(&self.state),
+            ))));
+        }
+        Ok(())
+    }
+
     /// Configure which temperature unit the device will use for its display. This does not affect
-    /// the Bluetooth interface.
+    /// the Bluetooth interface: temperatures in `RealTimeData` are always in degrees Celcius, so
+    /// convert them yourself if you want to show them in another unit.
+    ///
+    /// ```
+    /// use cloudbbq::{RealTimeData, TemperatureUnit};
+    ///
+    /// let data = RealTimeData {
+    ///     probe_temperatures: vec![Some(100.0), None],
+    /// };
+    /// let fahrenheit: Vec<Option<f32>> = data
+    ///     .probe_temperatures
+    ///     .iter()
+    ///     .map(|t| t.map(|t| TemperatureUnit::Fahrenheit.convert_from_celsius(t)))
+    ///     .collect();
+    /// assert_eq!(fahrenheit, vec![Some(212.0), None]);
+    /// ```
     pub async fn set_temperature_unit(&self, unit: TemperatureUnit) -> Result<(), BluetoothError> {
-        let argument = match unit {
-            TemperatureUnit::Celcius => UNITS_CELCIUS_ARGUMENT,
-            TemperatureUnit::Fahrenheit => UNITS_FAHRENHEIT_ARGUMENT,
-        };
-        let command = [SET_UNIT_COMMAND, argument, 0, 0, 0, 0];
-        self.bt_session
-            .write_characteristic_value(&self.setting_data_characteristic, command)
-            .await
+        self.write_setting_then(command_bytes::set_unit(&self.profile(), unit), |state| {
+            state.unit = Some(unit)
+        })
+        .await
     }
 
     /// Set the desired temperature range for the given temperature probe. If the temperature goes
-    /// outside the given range then the device will sound an alarm.
+    /// outside the given range then the device will sound an alarm: `range.start` is the low alarm,
+    /// and `range.end` the high alarm. Use `set_target` to alarm on only one side.
+    ///
+    /// Returns `Error::InvertedTargetRange` if `range.start` is above `range.end`, unless one of them
+    /// is the value meaning no alarm on that side, as sent by `set_target` with a `None` bound.
+    ///
+    /// If the device profile only supports whole-degree targets then the range is rounded to the
+    /// nearest degree.
+    ///
+    /// There is no known way to read the targets back from the device, so an app which needs to
+    /// know them after reconnecting should persist them itself. While connected, the targets set
+    /// are available from `target` and `targets`.
     pub async fn set_target_range(&self, probe: u8, range: Range<f32>) -> Result<(), Error> {
-        let bottom_bytes = encode_temperature(range.start)?;
-        let top_bytes = encode_temperature(range.end)?;
-        let value = [
-            SET_TARGET_TEMP_COMMAND,
-            probe,
-            bottom_bytes[0],
-            bottom_bytes[1],
-            top_bytes[0],
-            top_bytes[1],
-        ];
-        self.bt_session
-            .write_characteristic_value(&self.setting_data_characteristic, value)
+        let command = command_bytes::set_target_range(&self.profile(), probe, range.clone())?;
+        self.write_setting_then(command, |state| state.record_target(probe, range))
             .await?;
         Ok(())
     }
 
+    /// Set independent lower and upper target temperatures for the given temperature probe. If the
+    /// temperature goes below `low` or above `high` then the device will sound an alarm. A bound of
+    /// `None` means there is no alarm on that side, and if both are `None` the target is removed.
+    pub async fn set_target(
+        &self,
+        probe: u8,
+        low: Option<f32>,
+        high: Option<f32>,
+    ) -> Result<(), Error> {
+        self.set_target_range(probe, command_bytes::target_range(low, high))
+            .await
+    }
+
     /// Set the target temperature for the given temperature probe. Once the temperature goes above
     /// the given value the device will sound an alarm.
     pub async fn set_target_temp(&self, probe: u8, target: f32) -> Result<(), Error> {
@@ -178,6 +984,22 @@ impl BBQDevice {
             .await
     }
 
+    /// Set the target temperature for the given temperature probe like `set_target_temp`, but with
+    /// the target in the given unit rather than degrees Celsius.
+    ///
+    /// The device is always sent targets in Celsius, whatever unit it is displaying, so this
+    /// converts the target first. Targets are stored to 0.1°C, so a target in Fahrenheit may be
+    /// rounded slightly.
+    pub async fn set_target_temp_unit(
+        &self,
+        probe: u8,
+        target: f32,
+        unit: TemperatureUnit,
+    ) -> Result<(), Error> {
+        self.set_target_temp(probe, unit.convert_to_celsius(target))
+            .await
+    }
+
     /// Remove the target temperature setting for the given temperature probe.
     pub async fn remove_target(&self, probe: u8) -> Result<(), Error> {
         self.set_target_range(probe, TARGET_TEMP_NO_MINIMUM..TARGET_TEMP_NO_MAXIMUM)
@@ -186,213 +1008,3055 @@ impl BBQDevice {
 
     /// Enable or disable the device from sending real-time temperature data from its probes.
     pub async fn enable_real_time_data(&self, enable: bool) -> Result<(), BluetoothError> {
-        let argument = if enable { 0x01 } else { 0x00 };
-        let command = [REAL_TIME_DATA_COMMAND, argument, 0, 0, 0, 0];
-        self.bt_session
-            .write_characteristic_value(&self.setting_data_characteristic, command)
-            .await
+        let command = command_bytes::enable_real_time_data(&self.profile(), enable);
+        self.write_setting_then(command, |state| {
+            state.real_time = if enable {
+                RealTimeState::Enabled
+            } else {
+                RealTimeState::Disabled
+            };
+        })
+        .await
+    }
+
+    /// Enable real-time data for only the given probes.
+    ///
+    /// No device known so far can enable real-time data per probe, so this currently just enables
+    /// it globally and the device still sends the temperatures of all probes. Filter the readings
+    /// on the client instead, e.g. with `RealTimeData::connected_map`.
+    pub async fn enable_real_time_data_for(&self, probes: &[u8]) -> Result<(), BluetoothError> {
+        info!(
+            "Enabling real-time data for all probes, as per-probe selection of {:?} isn't supported",
+            probes
+        );
+        self.enable_real_time_data(true).await
+    }
+
+    /// Temporarily stop the device sending real-time data, if it is enabled.
+    ///
+    /// This sends the same command as `enable_real_time_data(false)`, but leaves notifications
+    /// running so that existing streams from `real_time` will continue once
+    /// `resume_real_time` is called.
+    pub async fn pause_real_time(&self) -> Result<(), BluetoothError> {
+        if self.state().pause_real_time() {
+            self.write_setting(command_bytes::enable_real_time_data(&self.profile(), false))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Resume real-time data after `pause_real_time`. This does nothing if it isn't paused.
+    pub async fn resume_real_time(&self) -> Result<(), BluetoothError> {
+        if self.state().resume_real_time() {
+            self.write_setting(command_bytes::enable_real_time_data(&self.profile(), true))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Return whether real-time data is currently paused by `pause_real_time`.
+    pub fn is_real_time_paused(&self) -> bool {
+        self.state().real_time == RealTimeState::Paused
+    }
+
+    /// Enable real-time data if it isn't already, returning its previous state to pass to
+    /// `restore_real_time` afterwards.
+    async fn enable_real_time_temporarily(&self) -> Result<RealTimeState, BluetoothError> {
+        let previous = self.state().real_time;
+        if previous != RealTimeState::Enabled {
+            self.enable_real_time_data(true).await?;
+        }
+        Ok(previous)
+    }
+
+    /// Put real-time data back to the given state from before `enable_real_time_temporarily`.
+    async fn restore_real_time(&self, previous: RealTimeState) -> Result<(), BluetoothError> {
+        match previous {
+            RealTimeState::Enabled => Ok(()),
+            RealTimeState::Disabled => self.enable_real_time_data(false).await,
+            RealTimeState::Paused => {
+                let command = command_bytes::enable_real_time_data(&self.profile(), false);
+                self.write_setting_then(command, |state| state.real_time = RealTimeState::Paused)
+                    .await
+            }
+        }
     }
 
     /// Request that the device report its current battery level. The result will come as a
     /// `SettingResult` event.
     pub async fn request_battery_level(&self) -> Result<(), BluetoothError> {
-        let command = [
-            REQUEST_PROPERTY_COMMAND,
-            BATTERY_LEVEL_PROPERTY_ID,
-            0,
-            0,
-            0,
-            0,
-        ];
-        self.bt_session
-            .write_characteristic_value(&self.setting_data_characteristic, command)
+        self.write_setting(command_bytes::request_battery_level(&self.profile()))
             .await
     }
 
-    /// Silence the alarm, if it is currently beeping.
-    pub async fn silence_alarm(&self) -> Result<(), BluetoothError> {
-        let command = [SILENCE_COMMAND, SILENCE_ARGUMENT, 0, 0, 0, 0];
-        self.bt_session
-            .write_characteristic_value(&self.setting_data_characteristic, command)
-            .await
+    /// Request that the device send its recorded history, which will come on the stream from
+    /// `history`.
+    ///
+    /// No command to do this is known for any device so far, so this returns
+    /// `Error::UnsupportedCommand` unless a profile with `request_history_command` has been set.
+    pub async fn request_history(&self) -> Result<(), Error> {
+        self.write_setting(command_bytes::request_history(&self.profile())?)
+            .await?;
+        Ok(())
     }
 
-    /// Get a stream of real time data from the device.
+    /// Get a stream of history records from the device, oldest first, after calling
+    /// `request_history`.
     ///
-    /// You must also call `enable_real_time_data(true)` to actually get some data.
-    pub async fn real_time(&self) -> Result<impl Stream<Item = RealTimeData>, BluetoothError> {
-        let real_time_data_characteristic = self.real_time_data_characteristic.clone();
+    /// History frames are assumed to be laid out like real-time data, with a record of the
+    /// temperature of each probe. A record may be split across several notifications, so they are
+    /// reassembled into records of the size of the most recent real-time reading if there is one, or
+    /// else each notification is taken as a single record. An empty notification marks the end of
+    /// the history, and ends the stream.
+    pub async fn history(&self) -> Result<impl Stream<Item = HistoryData>, BluetoothError> {
+        let history_data_characteristic = self.history_data_characteristic.clone();
         self.bt_session
-            .start_notify(&real_time_data_characteristic)
+            .start_notify(&history_data_characteristic)
             .await?;
+        self.state()
+            .notifying
+            .insert(history_data_characteristic.clone());
         let events = self
             .bt_session
-            .characteristic_event_stream(&real_time_data_characteristic)
+            .characteristic_event_stream(&history_data_characteristic)
             .await?;
-        Ok(StreamExt::filter_map(events, move |event| {
+        let frames = StreamExt::filter_map(events, move |event| {
             future::ready(match event {
                 BluetoothEvent::Characteristic {
                     id,
                     event: CharacteristicEvent::Value { value },
-                } if id == real_time_data_characteristic => RealTimeData::try_parse(&value),
+                } if id == history_data_characteristic => Some(value),
                 _ => {
                     info!("Unexpected Bluetooth event {:?}", event);
                     None
                 }
             })
-        }))
+        });
+        let (record_length, byte_order) = {
+            let state = self.state();
+            let record_length = state
+                .last_reading
+                .as_ref()
+                .map(|reading| reading.probe_temperatures.len() * 2)
+                .filter(|&length| length > 0);
+            (record_length, state.profile.byte_order)
+        };
+        Ok(history_records(frames, record_length, byte_order))
     }
 
-    /// Get a stream of setting results from the device. This includes responses to commands,
-    /// battery level notifications, and notifications that the alarm has been silenced.
-    pub async fn setting_results(
-        &self,
-    ) -> Result<impl Stream<Item = SettingResult>, BluetoothError> {
-        let setting_result_characteristic = self.setting_result_characteristic.clone();
-        self.bt_session
-            .start_notify(&setting_result_characteristic)
-            .await?;
-        let events = self
-            .bt_session
-            .characteristic_event_stream(&setting_result_characteristic)
-            .await?;
-        Ok(StreamExt::filter_map(events, move |event| {
-            future::ready(match event {
-                BluetoothEvent::Characteristic {
-                    id,
-                    event: CharacteristicEvent::Value { value },
-                } if id == setting_result_characteristic => SettingResult::try_parse(&value),
-                _ => {
-                    info!("Unexpected Bluetooth event {:?}", event);
+    /// Silence the alarm, if it is currently beeping.
+    pub async fn silence_alarm(&self) -> Result<(), BluetoothError> {
+        self.write_setting(command_bytes::silence_alarm(&self.profile()))
+            .await
+    }
+
+    /// Get a stream of real time data from the device.
+    ///
+    /// You must also call `enable_real_time_data(true)` to actually get some data.
+    ///
+    /// Some devices send a reading or two as soon as notifications are started, before
+    /// `authenticate` has been called. These are discarded, as are any readings which arrive while
+    /// the authentication is still being written, so the stream only contains readings from after
+    /// the device has accepted the connection.
+    ///
+    /// Readings are queued until they are consumed rather than dropped, however slow the consumer
+    /// is. For a bounded buffer shared between several consumers, see `SessionManager::events`.
+    ///
+    /// Any calibration offsets set with `set_global_offset` or `set_probe_offset` have already been
+    /// added to the temperatures.
+    pub async fn real_time(&self) -> Result<impl Stream<Item = RealTimeData>, BluetoothError> {
+        Ok(real_time_readings(
+            self.real_time_frames().await?,
+            self.state.clone(),
+        ))
+    }
+
+    /// Start notifications for the real-time data characteristic, and get a stream of the raw frames.
+    async fn real_time_frames(&self) -> Result<impl Stream<Item = Vec<u8>>, BluetoothError> {
+        let real_time_data_characteristic = self.real_time_data_characteristic.clone();
+        self.bt_session
+            .start_notify(&real_time_data_characteristic)
+            .await?;
+        self.state()
+            .notifying
+            .insert(real_time_data_characteristic.clone());
+        let events = self
+            .bt_session
+            .characteristic_event_stream(&real_time_data_characteristic)
+            .await?;
+        Ok(characteristic_values(
+            events,
+            real_time_data_characteristic,
+            "real-time data",
+            REAL_TIME_DATA_UUID,
+            Arc::downgrade(&self.state),
+        ))
+    }
+
+    /// Work out the byte order which the device uses for temperatures from the first few real-time
+    /// data frames, and switch the profile to it if it differs from the current one.
+    ///
+    /// A byte order is only switched to if it gives plausible temperatures for all the frames
+    /// while the current one doesn't, so frames which decode to plausible temperatures either way
+    /// leave the profile unchanged. Note that the profile's byte order is used for commands as well
+    /// as readings.
+    ///
+    /// As with `real_time`, you must also call `enable_real_time_data(true)` for the device to send
+    /// any frames.
+    pub async fn detect_byte_order(&self) -> Result<ByteOrder, BluetoothError> {
+        let frames: Vec<Vec<u8>> = self
+            .real_time_frames()
+            .await?
+            .take(BYTE_ORDER_DETECTION_FRAMES)
+            .collect()
+            .await;
+        let mut state = self.state();
+        let current = state.profile.byte_order;
+        let detected = infer_byte_order(&frames, current);
+        if detected != current {
+            info!(
+                "Switching from {:?} to {:?} based on readings {:?}",
+                current, detected, frames
+            );
+            state.profile.byte_order = detected;
+        }
+        Ok(detected)
+    }
+
+    /// Get a stream of the temperature of just the given probe from each reading from `real_time`,
+    /// or `None` if it is disconnected or the device doesn't have that many probes.
+    pub async fn probe_stream(
+        &self,
+        probe: u8,
+    ) -> Result<impl Stream<Item = Option<f32>>, BluetoothError> {
+        Ok(self.real_time().await?.probe(probe))
+    }
+
+    /// Get a stream of real time data from the device like `real_time`, but with typed temperatures
+    /// which can be read in either unit.
+    pub async fn real_time_typed(
+        &self,
+    ) -> Result<impl Stream<Item = RealTimeReading>, BluetoothError> {
+        Ok(self.real_time().await?.map(RealTimeReading::from))
+    }
+
+    /// Read the current raw value of the real-time data characteristic, without parsing it.
+    ///
+    /// This is intended for capturing frames for bug reports, or for parsing them with custom
+    /// logic. Not all devices support reading the characteristic directly
+    /// rather than through notifications.
+    pub async fn read_real_time_raw(&self) -> Result<Vec<u8>, BluetoothError> {
+        self.bt_session
+            .read_characteristic_value(&self.real_time_data_characteristic)
+            .await
+    }
+
+    /// Get a stream of setting results from the device. This includes responses to commands,
+    /// battery level notifications, and notifications that the alarm has been silenced.
+    pub async fn setting_results(
+        &self,
+    ) -> Result<impl Stream<Item = SettingResult>, BluetoothError> {
+        let setting_result_characteristic = self.setting_result_characteristic.clone();
+        self.bt_session
+            .start_notify(&setting_result_characteristic)
+            .await?;
+        self.state()
+            .notifying
+            .insert(setting_result_characteristic.clone());
+        let events = self
+            .bt_session
+            .characteristic_event_stream(&setting_result_characteristic)
+            .await?;
+        Ok(setting_results(
+            events,
+            setting_result_characteristic,
+            Arc::downgrade(&self.state),
+        ))
+    }
+
+    /// Get a single stream of both real-time data and setting results from the device, for apps
+    /// which want to handle everything in one place.
+    ///
+    /// Notifications are started on both characteristics before this returns, so nothing sent
+    /// after it returns is missed. As with `real_time`, you must also call
+    /// `enable_real_time_data(true)` to actually get real-time data.
+    pub async fn events(&self) -> Result<impl Stream<Item = BBQEvent>, BluetoothError> {
+        let readings = self.real_time().await?.map(BBQEvent::RealTime);
+        let setting_results = self.setting_results().await?.map(BBQEvent::Setting);
+        Ok(stream::select(readings, setting_results))
+    }
+
+    /// Get a single stream of all alarm-related events, combining those reported by the device with
+    /// those derived from the target ranges set by this client.
+    ///
+    /// `AlarmEvent::DeviceAlarmSilenced` comes from the device's setting results. The
+    /// `SoftwareThreshold*` events are derived by comparing real-time readings against the targets
+    /// set with `set_target_range` and friends, as the device doesn't report when its alarm starts
+    /// sounding. The `SafetyCeiling*` events are likewise derived from the ceilings set with
+    /// `set_safety_ceiling`, independently of the targets. You must also call
+    /// `enable_real_time_data(true)` for these to be produced.
+    pub async fn alarms(&self) -> Result<impl Stream<Item = AlarmEvent>, BluetoothError> {
+        let setting_results = self.setting_results().await?.map(Either::Left);
+        let readings = self.real_time().await?.map(Either::Right);
+        Ok(alarm_events(
+            stream::select(setting_results, readings),
+            self.state.clone(),
+        ))
+    }
+
+    /// Get a stream of real time data from the device, like `real_time`, but which yields
+    /// `Error::Timeout` if no reading arrives within the given idle timeout.
+    ///
+    /// This is useful for unattended monitoring, to notice a stalled link and reconnect rather than
+    /// silently waiting forever. See `RealTimeStreamExt::with_idle_timeout` for details.
+    pub async fn real_time_with_timeout(
+        &self,
+        idle_timeout: Duration,
+    ) -> Result<impl Stream<Item = Result<RealTimeData, Error>>, BluetoothError> {
+        Ok(self.real_time().await?.with_idle_timeout(idle_timeout))
+    }
+
+    /// Authenticate with the device and find out what it can tell us about itself.
+    ///
+    /// If any targets have already been set for probes which the device doesn't have, a warning is
+    /// logged.
+    ///
+    /// This requests the battery level and enables real-time data to learn the number of probes,
+    /// then waits up to a few seconds for the responses. Any properties which the device doesn't
+    /// report in that time are left as `None`. Real-time data is put back as it was before
+    /// returning, so it is only left enabled if it already was.
+    pub async fn handshake(&self) -> Result<HandshakeInfo, Error> {
+        self.authenticate().await?;
+        let setting_results = self.setting_results().await?;
+        let readings = self.real_time().await?;
+        self.request_battery_level().await?;
+        let previous_real_time = self.enable_real_time_temporarily().await?;
+        let mut info = HandshakeInfo::default();
+        // Whatever arrived before the timeout is still useful, so ignore the timeout error.
+        let _ = tokio::time::timeout(
+            HANDSHAKE_TIMEOUT,
+            collect_handshake_info(&mut info, setting_results, readings),
+        )
+        .await;
+        self.restore_real_time(previous_real_time).await?;
+        if let Some(probe_count) = info.probe_count {
+            if let Err(e) = self.check_targets(probe_count) {
+                warn!("{}", e);
+            }
+        }
+        Ok(info)
+    }
+
+    /// Check that all the targets which this client has set are for probes which the device
+    /// actually has, given the number of probes it reports in its real-time data. A target on a
+    /// nonexistent probe silently does nothing, so this catches misconfigurations.
+    ///
+    /// Returns `Error::NoSuchProbe` for the lowest such probe, if any. `handshake` also checks this
+    /// and logs a warning on failure.
+    pub fn check_targets(&self, probe_count: usize) -> Result<(), Error> {
+        check_targets(&self.state().targets, probe_count)
+    }
+
+    /// Wait until the given probe reaches at least the given temperature, or the timeout (if any)
+    /// elapses.
+    ///
+    /// This enables real-time data while waiting, and puts it back as it was before returning. If
+    /// that fails then the error is logged, but the result of the wait is still returned. Returns
+    /// `Error::ProbeDisconnected` if a reading arrives in which the probe is not connected.
+    pub async fn wait_until(
+        &self,
+        probe: u8,
+        target: f32,
+        timeout: Option<Duration>,
+    ) -> Result<(), Error> {
+        let readings = self.real_time().await?;
+        let previous_real_time = self.enable_real_time_temporarily().await?;
+        let wait = wait_for_temperature(readings, probe, target);
+        let result = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, wait)
+                .await
+                .unwrap_or(Err(Error::Timeout)),
+            None => wait.await,
+        };
+        if let Err(e) = self.restore_real_time(previous_real_time).await {
+            warn!("Failed to restore real-time data after waiting: {}", e);
+        }
+        result
+    }
+
+    /// Get a stream which yields an item whenever the device disconnects.
+    ///
+    /// This can be used to notice promptly when the connection is lost, e.g. to reconnect.
+    pub async fn on_disconnect(&self) -> Result<impl Stream<Item = ()>, BluetoothError> {
+        let device_id = self.device_id();
+        let events = self.bt_session.device_event_stream(&device_id).await?;
+        Ok(disconnections(events, device_id))
+    }
+
+    /// Get a stream of the device's signal strength (RSSI), polled at the given interval.
+    ///
+    /// This polls the RSSI which BlueZ reports for the device rather than being notified of
+    /// changes. BlueZ doesn't always know the RSSI of a connected device, in which case this yields
+    /// `None`. The first value is yielded immediately.
+    pub fn rssi_stream(
+        &self,
+        interval: Duration,
+    ) -> impl Stream<Item = Result<Option<i16>, BluetoothError>> {
+        let bt_session = self.bt_session.clone();
+        let device_id = self.device_id();
+        stream::unfold(None, move |ticks| {
+            let bt_session = bt_session.clone();
+            let device_id = device_id.clone();
+            async move {
+                let mut ticks = ticks.unwrap_or_else(|| tokio::time::interval(interval));
+                ticks.tick().await;
+                let rssi = bt_session
+                    .get_device_info(&device_id)
+                    .await
+                    .map(|info| info.rssi);
+                Some((rssi, Some(ticks)))
+            }
+        })
+    }
+
+    /// Describe the characteristics of the device's BBQ service, their properties, and whether this
+    /// client has started notifications on them. This is intended for diagnosing problems with
+    /// unusual devices.
+    pub async fn describe(&self) -> Result<String, BluetoothError> {
+        let service = self.setting_data_characteristic.service();
+        let characteristics = self.bt_session.get_characteristics(&service).await?;
+        Ok(describe_characteristics(
+            &characteristics,
+            &self.state().notifying,
+        ))
+    }
+
+    /// Combine the given reading with the targets, labels and colors which have been set for each
+    /// probe.
+    ///
+    /// Targets are only known if they were set by this client, as they can't be read back from the
+    /// device.
+    pub fn probe_states(&self, reading: &RealTimeData) -> Vec<ProbeState> {
+        let state = self.state();
+        probe_states(reading, &state.targets, &state.labels, &state.colors)
+    }
+
+    /// Take a snapshot of the client-side state for this device, so that it can be saved and later
+    /// restored with `restore_snapshot`, e.g. across an app restart in the middle of a cook.
+    pub fn snapshot(&self) -> DeviceSnapshot {
+        let state = self.state();
+        DeviceSnapshot {
+            targets: state.targets.clone(),
+            labels: state.labels.clone(),
+            colors: state.colors.clone(),
+            unit: state.unit,
+            last_reading: state.last_reading.clone(),
+        }
+    }
+
+    /// Restore a snapshot previously taken with `snapshot`.
+    ///
+    /// This restores the client-side state, and sends the snapshot's targets and display unit to
+    /// the device in case it has been reset. Targets which this client has set since the snapshot
+    /// was taken but which aren't in it are left alone.
+    pub async fn restore_snapshot(&self, snapshot: DeviceSnapshot) -> Result<(), Error> {
+        {
+            let mut state = self.state();
+            state.labels = snapshot.labels;
+            state.colors = snapshot.colors;
+            if state.last_reading.is_none() {
+                state.last_reading = snapshot.last_reading;
+            }
+        }
+        if let Some(unit) = snapshot.unit {
+            self.set_temperature_unit(unit).await?;
+        }
+        let mut targets: Vec<_> = snapshot.targets.into_iter().collect();
+        targets.sort_by_key(|(probe, _)| *probe);
+        for (probe, range) in targets {
+            self.set_target_range(probe, range).await?;
+        }
+        Ok(())
+    }
+
+    /// Return how long ago the most recent real-time reading was received from the device, or
+    /// `None` if none has been received yet.
+    ///
+    /// This can be used to warn about stale data if the link has silently stalled. Only readings
+    /// from streams returned by `real_time` (and the methods built on it) are counted.
+    pub fn last_reading_age(&self) -> Option<Duration> {
+        self.state().last_reading_age(Instant::now())
+    }
+
+    /// Keep the given number of the most recent readings in a buffer, for `recent_readings`, or pass
+    /// 0 to disable the buffer and free it. The buffer is disabled by default.
+    ///
+    /// This is a client-side buffer of readings from `real_time` and the streams built on it, unlike
+    /// the history which the device records itself. Each reading takes a few tens of bytes plus 8
+    /// bytes per probe, so a capacity of a few thousand readings is fine for most apps.
+    pub fn enable_history_buffer(&self, capacity: usize) {
+        self.state().set_recent_readings_capacity(capacity);
+    }
+
+    /// Get the readings in the history buffer and when they were received, oldest first. This is
+    /// empty unless the buffer has been enabled with `enable_history_buffer`.
+    pub fn recent_readings(&self) -> Vec<(SystemTime, RealTimeData)> {
+        self.state().recent_readings.iter().cloned().collect()
+    }
+
+    /// Get the target range which this client has set for the given probe, if any.
+    ///
+    /// Targets can't be read back from the device, so this only knows about targets set through
+    /// this `BBQDevice` or its clones. Removing a target with `remove_target` removes it here too.
+    pub fn target(&self, probe: u8) -> Option<Range<f32>> {
+        self.state().targets.get(&probe).cloned()
+    }
+
+    /// Return the number of probes which have a target set by this client, and so will sound an
+    /// alarm if they go out of range.
+    ///
+    /// There is no known way to ask the device for its alarm configuration, so this only counts
+    /// targets set through this `BBQDevice` or its clones.
+    pub fn active_alarm_count(&self) -> usize {
+        self.state().targets.len()
+    }
+
+    /// Get all the target ranges which this client has set, keyed by probe index.
+    pub fn targets(&self) -> HashMap<u8, Range<f32>> {
+        self.state().targets.clone()
+    }
+
+    /// Set a safety ceiling in degrees Celsius for the given probe, e.g. to warn if the pit gets
+    /// dangerously hot whatever the target for the food is.
+    ///
+    /// This is tracked by the client, separately from the probe's target, and is never sent to the
+    /// device. `alarms` produces `AlarmEvent::SafetyCeilingExceeded` when the probe's temperature
+    /// goes above the ceiling.
+    pub fn set_safety_ceiling(&self, probe: u8, ceiling: f32) {
+        self.state().safety_ceilings.insert(probe, ceiling);
+    }
+
+    /// Remove the safety ceiling for the given probe, if any.
+    pub fn remove_safety_ceiling(&self, probe: u8) {
+        self.state().safety_ceilings.remove(&probe);
+    }
+
+    /// Get the safety ceiling set for the given probe, if any.
+    pub fn safety_ceiling(&self, probe: u8) -> Option<f32> {
+        self.state().safety_ceilings.get(&probe).copied()
+    }
+
+    /// Return the indices of the probes whose temperatures in the given reading are outside the
+    /// targets set for them by this client.
+    ///
+    /// This is worked out on the client from the cached targets, so it works even if the device's
+    /// own alarm has been silenced.
+    pub fn alarming_probes(&self, reading: &RealTimeData) -> Vec<u8> {
+        alarming_probes(reading, &self.state().targets)
+    }
+
+    /// Give the given probe a human-readable name, such as "brisket" or "ambient".
+    ///
+    /// Labels are client-side metadata only, and are not sent to the device.
+    pub fn set_probe_label(&self, probe: u8, name: impl Into<String>) {
+        self.state().labels.set(probe, name);
+    }
+
+    /// Get the label previously set for the given probe, if any.
+    pub fn probe_label(&self, probe: u8) -> Option<String> {
+        self.state().labels.get(probe).map(ToOwned::to_owned)
+    }
+
+    /// Get a copy of all probe labels, e.g. to persist them.
+    pub fn probe_labels(&self) -> ProbeLabels {
+        self.state().labels.clone()
+    }
+
+    /// Replace all probe labels, e.g. with ones previously persisted.
+    pub fn set_probe_labels(&self, labels: ProbeLabels) {
+        self.state().labels = labels;
+    }
+
+    /// Set the color to show the given probe in, or `None` to remove it.
+    ///
+    /// Like labels, colors are client-side metadata only, and are not sent to the device.
+    pub fn set_probe_color(&self, probe: u8, color: Option<Color>) {
+        self.state().set_color(probe, color);
+    }
+
+    /// Get the color previously set for the given probe, if any.
+    pub fn probe_color(&self, probe: u8) -> Option<Color> {
+        self.state().colors.get(&probe).copied()
+    }
+
+    /// Set a calibration offset in degrees Celsius to add to the readings from all probes, e.g. to
+    /// correct for the boiling point of water at altitude. Probes with their own offset set by
+    /// `set_probe_offset` use that instead.
+    ///
+    /// No calibration command is known for the device, so this only affects readings from
+    /// `real_time` and the streams built on it. It is not applied to targets.
+    pub fn set_global_offset(&self, offset: f32) {
+        self.state().global_offset = offset;
+    }
+
+    /// Set a calibration offset in degrees Celsius to add to the readings from the given probe,
+    /// overriding the global offset, or `None` to go back to using the global offset.
+    pub fn set_probe_offset(&self, probe: u8, offset: Option<f32>) {
+        let mut state = self.state();
+        match offset {
+            Some(offset) => state.probe_offsets.insert(probe, offset),
+            None => state.probe_offsets.remove(&probe),
+        };
+    }
+
+    /// Get the calibration offset which is added to readings from the given probe.
+    pub fn probe_offset(&self, probe: u8) -> f32 {
+        self.state().offset(probe)
+    }
+}
+
+/// Everything known about a single probe, for displaying in a UI.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProbeState {
+    /// The index of the probe.
+    pub index: u8,
+    /// The current temperature of the probe in degrees Celcius, or `None` if it is disconnected.
+    pub temperature: Option<f32>,
+    /// The target range set for the probe, if any.
+    pub target: Option<Range<f32>>,
+    /// The label set for the probe, if any.
+    pub label: Option<String>,
+    /// The color set for the probe, if any.
+    pub color: Option<Color>,
+}
+
+/// A color in which to show a probe in a UI.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct Color {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl Color {
+    /// Create a color from the given red, green and blue components.
+    pub const fn rgb(red: u8, green: u8, blue: u8) -> Self {
+        Self { red, green, blue }
+    }
+}
+
+/// The client-side state of a device, from `BBQDevice::snapshot`.
+///
+/// With the `serde` feature this can be serialized, to persist it across app restarts.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct DeviceSnapshot {
+    /// The target ranges which have been set for each probe.
+    pub targets: HashMap<u8, Range<f32>>,
+    /// The labels which have been set for the probes.
+    pub labels: ProbeLabels,
+    /// The colors which have been set for each probe.
+    pub colors: HashMap<u8, Color>,
+    /// The display unit which has been set, if any.
+    pub unit: Option<TemperatureUnit>,
+    /// The most recent reading from the device, if any.
+    pub last_reading: Option<RealTimeData>,
+}
+
+/// An event from the device, from `BBQDevice::events`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BBQEvent {
+    /// A reading of real-time data.
+    RealTime(RealTimeData),
+    /// A setting result.
+    Setting(SettingResult),
+}
+
+/// An alarm-related event, from `BBQDevice::alarms`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AlarmEvent {
+    /// The button on the device was pressed to silence its alarm, for the given probe if the device
+    /// says which.
+    DeviceAlarmSilenced { probe: Option<u8> },
+    /// The temperature of the given probe has gone outside the target range set for it.
+    SoftwareThresholdCrossed {
+        probe: u8,
+        temperature: f32,
+        range: Range<f32>,
+    },
+    /// The temperature of the given probe has come back inside the target range set for it.
+    SoftwareThresholdCleared {
+        probe: u8,
+        temperature: f32,
+        range: Range<f32>,
+    },
+    /// The temperature of the given probe has gone above the safety ceiling set for it.
+    SafetyCeilingExceeded {
+        probe: u8,
+        temperature: f32,
+        ceiling: f32,
+    },
+    /// The temperature of the given probe has come back down to or below the safety ceiling set for
+    /// it.
+    SafetyCeilingCleared {
+        probe: u8,
+        temperature: f32,
+        ceiling: f32,
+    },
+}
+
+/// The state of a single probe, as produced by `RealTimeStreamExt::with_last_known`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProbeReading {
+    /// Whether the probe is currently connected.
+    pub connected: bool,
+    /// The current temperature of the probe if it is connected, otherwise the last temperature it
+    /// reported before it was disconnected, if any.
+    pub temperature: Option<f32>,
+}
+
+/// What a device reported about itself during `BBQDevice::handshake`.
+///
+/// The device has no way to report which temperature unit it is using for its display, so that is
+/// not included.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct HandshakeInfo {
+    /// The current battery voltage, if the device reported it.
+    pub current_voltage: Option<u16>,
+    /// The maximum battery voltage, if the device reported it.
+    pub max_voltage: Option<u16>,
+    /// The number of probes which the device has, whether or not they are connected.
+    pub probe_count: Option<usize>,
+}
+
+impl HandshakeInfo {
+    fn is_complete(&self) -> bool {
+        self.current_voltage.is_some() && self.max_voltage.is_some() && self.probe_count.is_some()
+    }
+}
+
+/// Human-readable names for temperature probes, keyed by probe index.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct ProbeLabels {
+    labels: HashMap<u8, String>,
+}
+
+impl ProbeLabels {
+    /// Set the label for the given probe, replacing any previous label.
+    pub fn set(&mut self, probe: u8, name: impl Into<String>) {
+        self.labels.insert(probe, name.into());
+    }
+
+    /// Get the label for the given probe, if it has one.
+    pub fn get(&self, probe: u8) -> Option<&str> {
+        self.labels.get(&probe).map(String::as_str)
+    }
+
+    /// Remove the label for the given probe, returning it if there was one.
+    pub fn remove(&mut self, probe: u8) -> Option<String> {
+        self.labels.remove(&probe)
+    }
+}
+
+/// A temperature, which can be read in either unit.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct Temperature {
+    celsius: f32,
+}
+
+impl Temperature {
+    /// Create a temperature from a value in degrees Celsius.
+    pub fn from_celsius(celsius: f32) -> Self {
+        Self { celsius }
+    }
+
+    /// Create a temperature from a value in the given unit.
+    pub fn from_unit(temperature: f32, unit: TemperatureUnit) -> Self {
+        Self::from_celsius(unit.convert_to_celsius(temperature))
+    }
+
+    /// Get the temperature in degrees Celsius.
+    pub fn celsius(self) -> f32 {
+        self.celsius
+    }
+
+    /// Get the temperature in degrees Fahrenheit.
+    pub fn fahrenheit(self) -> f32 {
+        self.in_unit(TemperatureUnit::Fahrenheit)
+    }
+
+    /// Get the temperature in the given unit.
+    pub fn in_unit(self, unit: TemperatureUnit) -> f32 {
+        unit.convert_from_celsius(self.celsius)
+    }
+}
+
+/// A data point from a BBQ device like `RealTimeData`, but with typed temperatures.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct RealTimeReading {
+    /// The current temperature of each probe, or None if the probe is disconnected.
+    pub probes: Vec<Option<Temperature>>,
+}
+
+impl RealTimeReading {
+    /// Return whether the given probe is connected.
+    pub fn is_connected(&self, probe: usize) -> bool {
+        matches!(self.probes.get(probe), Some(Some(_)))
+    }
+}
+
+impl From<RealTimeData> for RealTimeReading {
+    fn from(data: RealTimeData) -> Self {
+        Self {
+            probes: data
+                .probe_temperatures
+                .into_iter()
+                .map(|temperature| temperature.map(Temperature::from_celsius))
+                .collect(),
+        }
+    }
+}
+
+/// The temperature unit which the thermometer uses for its display.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub enum TemperatureUnit {
+    /// ºC
+    Celcius,
+    /// ºF
+    Fahrenheit,
+}
+
+impl TemperatureUnit {
+    /// Convert the given temperature in degrees Celcius to this unit.
+    pub fn convert_from_celsius(self, celsius: f32) -> f32 {
+        match self {
+            TemperatureUnit::Celcius => celsius,
+            TemperatureUnit::Fahrenheit => celsius * 9.0 / 5.0 + 32.0,
+        }
+    }
+
+    /// Convert the given temperature in this unit to degrees Celcius.
+    pub fn convert_to_celsius(self, temperature: f32) -> f32 {
+        match self {
+            TemperatureUnit::Celcius => temperature,
+            TemperatureUnit::Fahrenheit => (temperature - 32.0) * 5.0 / 9.0,
+        }
+    }
+}
+
+impl FromStr for TemperatureUnit {
+    type Err = ParseTemperatureUnitError;
+
+    /// Parse a temperature unit from its name or initial, ignoring case. The spelling of the
+    /// `Celcius` variant is accepted too.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "c" | "celsius" | "celcius" => Ok(TemperatureUnit::Celcius),
+            "f" | "fahrenheit" => Ok(TemperatureUnit::Fahrenheit),
+            _ => Err(ParseTemperatureUnitError(s.to_owned())),
+        }
+    }
+}
+
+impl Display for TemperatureUnit {
+    /// Format the unit as its symbol, e.g. "°C".
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TemperatureUnit::Celcius => f.write_str("°C"),
+            TemperatureUnit::Fahrenheit => f.write_str("°F"),
+        }
+    }
+}
+
+/// A data point from a BBQ device, giving the temperature of all connected probes.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct RealTimeData {
+    /// The current temperature of each probe in degrees Celcius, or None if the probe is
+    /// disconnected.
+    pub probe_temperatures: Vec<Option<f32>>,
+}
+
+impl RealTimeData {
+    fn try_parse(value: &[u8], byte_order: ByteOrder) -> Option<RealTimeData> {
+        if value.len() % 2 != 0 {
+            return None;
+        }
+        Some(RealTimeData {
+            probe_temperatures: value
+                .chunks_exact(2)
+                .map(|bytes| {
+                    let temperature = decode_temperature(bytes.try_into().unwrap(), byte_order);
+                    if temperature == ABSENT_PROBE_VALUE {
+                        None
+                    } else {
+                        Some(temperature)
+                    }
+                })
+                .collect(),
+        })
+    }
+
+    /// Format the temperatures of all probes on one line, using the given labels for probes which
+    /// have one, e.g. `"brisket: 51.3°C, Probe 1: disconnected"`.
+    pub fn format_with_labels(&self, labels: &ProbeLabels) -> String {
+        self.probe_temperatures
+            .iter()
+            .enumerate()
+            .map(|(index, temperature)| {
+                let label = u8::try_from(index)
+                    .ok()
+                    .and_then(|probe| labels.get(probe))
+                    .map_or_else(|| format!("Probe {}", index), ToOwned::to_owned);
+                match temperature {
+                    Some(temperature) => format!("{}: {:.1}°C", label, temperature),
+                    None => format!("{}: disconnected", label),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Return the temperatures of all connected probes, keyed by probe index.
+    pub fn connected_map(&self) -> HashMap<usize, f32> {
+        self.probe_temperatures
+            .iter()
+            .enumerate()
+            .filter_map(|(index, temperature)| temperature.map(|t| (index, t)))
+            .collect()
+    }
+
+    /// Return the temperature of each probe in tenths of a degree Celsius, as sent by the device.
+    ///
+    /// Unlike `RealTimeData` itself this implements `Eq` and `Hash`, so it can be used to
+    /// deduplicate or cache readings without the pitfalls of comparing floats.
+    pub fn fingerprint(&self) -> Vec<Option<i16>> {
+        self.probe_temperatures
+            .iter()
+            .map(|temperature| temperature.map(|t| (t * 10.0).round() as i16))
+            .collect()
+    }
+
+    /// Return whether each probe is connected.
+    pub fn probe_connected(&self) -> Vec<bool> {
+        self.probe_temperatures
+            .iter()
+            .map(Option::is_some)
+            .collect()
+    }
+
+    /// Format this reading as a line of InfluxDB line protocol, with a `probe<N>` field in degrees
+    /// Celsius for each connected probe and the given timestamp in nanoseconds, e.g.
+    /// `"bbq probe0=51.3,probe2=22 1700000000000000000"`.
+    ///
+    /// Line protocol doesn't allow a line without fields, so if no probes are connected this
+    /// returns an empty string. Commas and spaces in the measurement name are escaped, and times
+    /// before the Unix epoch are written as 0.
+    pub fn to_influx_line(&self, measurement: &str, timestamp: SystemTime) -> String {
+        let fields = self
+            .probe_temperatures
+            .iter()
+            .enumerate()
+            .filter_map(|(index, temperature)| temperature.map(|t| format!("probe{}={}", index, t)))
+            .collect::<Vec<_>>();
+        if fields.is_empty() {
+            return String::new();
+        }
+        let nanoseconds = timestamp
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_nanos());
+        format!(
+            "{} {} {}",
+            measurement.replace(',', "\\,").replace(' ', "\\ "),
+            fields.join(","),
+            nanoseconds
+        )
+    }
+}
+
+/// A record from the history which the device has recorded, from `BBQDevice::history`.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct HistoryData {
+    /// The temperature of each probe in degrees Celcius, or None if the probe was disconnected.
+    pub probe_temperatures: Vec<Option<f32>>,
+}
+
+impl HistoryData {
+    fn try_parse(value: &[u8], byte_order: ByteOrder) -> Option<HistoryData> {
+        if value.is_empty() {
+            return None;
+        }
+        RealTimeData::try_parse(value, byte_order).map(|reading| HistoryData {
+            probe_temperatures: reading.probe_temperatures,
+        })
+    }
+}
+
+/// A response to some command sent to the device, or a notification.
+///
+/// With the `serde` feature this is serialized with a `type` field naming the variant, e.g.
+/// `{"type":"BatteryLevel","current_voltage":5979,"max_voltage":6550}`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize), serde(tag = "type"))]
+pub enum SettingResult {
+    /// A confirmation that the given command has been received.
+    AcknowledgeCommand {
+        command_id: u8,
+        success: bool,
+        /// The status given in the rest of the acknowledgement.
+        status: AckStatus,
+    },
+    /// The current battery level of the device.
+    ///
+    /// The last byte of the frame is always zero on devices known so far, so there is no known way
+    /// to tell whether the device is charging.
+    BatteryLevel {
+        current_voltage: u16,
+        max_voltage: u16,
+    },
+    /// A notification that the button on the device has been pressed to stop the target temperature
+    /// alarm sounding.
+    SilencePressed {
+        /// The probe whose alarm was silenced, if the device says. Devices which silence all alarms
+        /// at once send `None`.
+        probe: Option<u8>,
+    },
+    /// A setting result whose type isn't recognised, such as the response to a property request
+    /// not yet understood, given as the raw frame.
+    Unknown { raw: Vec<u8> },
+}
+
+impl SettingResult {
+    /// Return the battery level as a percentage of the maximum voltage, capped at 100, if
+    /// this is a `BatteryLevel` result with a non-zero maximum voltage.
+    pub fn battery_percentage(&self) -> Option<f32> {
+        match *self {
+            SettingResult::BatteryLevel {
+                current_voltage,
+                max_voltage,
+            } if max_voltage != 0 => {
+                Some((f32::from(current_voltage) / f32::from(max_voltage) * 100.0).min(100.0))
+            }
+            _ => None,
+        }
+    }
+
+    fn try_parse(value: &[u8]) -> Option<SettingResult> {
+        if value.len() != 6 {
+            return None;
+        }
+        let id = match SettingResultId::try_from(value[0]) {
+            Ok(id) => id,
+            Err(_) => {
+                info!("Unrecognised setting result: {:?}", value);
+                return Some(SettingResult::Unknown {
+                    raw: value.to_owned(),
+                });
+            }
+        };
+        match id {
+            SettingResultId::Acknowledge => {
+                let status = AckStatus::parse(value[2..].try_into().unwrap());
+                if let AckStatus::Unknown(_) = status {
+                    info!("Unrecognised acknowledge: {:?}", value);
+                }
+                Some(SettingResult::AcknowledgeCommand {
+                    command_id: value[1],
+                    success: status == AckStatus::Success,
+                    status,
+                })
+            }
+            SettingResultId::BatteryLevel => Some(SettingResult::BatteryLevel {
+                current_voltage: u16::from_le_bytes(value[1..=2].try_into().unwrap()),
+                max_voltage: u16::from_le_bytes(value[3..=4].try_into().unwrap()),
+            }),
+            SettingResultId::SilencePressed => {
+                // The second byte is either the index of a probe, or 0xFF for all of them.
+                if value[2..] == [0, 0, 0, 0] {
+                    let probe = if value[1] == SILENCE_ARGUMENT {
+                        None
+                    } else {
+                        Some(value[1])
+                    };
+                    Some(SettingResult::SilencePressed { probe })
+                } else {
+                    info!("Unrecognised silence setting result: {:?}", value);
                     None
                 }
+            }
+        }
+    }
+}
+
+/// The status of a `SettingResult::AcknowledgeCommand`, from the bytes after the command ID.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub enum AckStatus {
+    /// The command succeeded.
+    Success,
+    /// The command failed because it referred to a probe which the device doesn't have.
+    InvalidProbe,
+    /// The command failed with some status not yet understood, given as the raw bytes.
+    Unknown([u8; 4]),
+}
+
+impl AckStatus {
+    fn parse(bytes: [u8; 4]) -> Self {
+        match bytes {
+            [0, 0, 0, 0] => Self::Success,
+            [5, 0, 0, 0] => Self::InvalidProbe,
+            _ => Self::Unknown(bytes),
+        }
+    }
+}
+
+/// Fill in the given `HandshakeInfo` from setting results and readings until it is complete or the
+/// streams end.
+async fn collect_handshake_info(
+    info: &mut HandshakeInfo,
+    setting_results: impl Stream<Item = SettingResult>,
+    readings: impl Stream<Item = RealTimeData>,
+) {
+    let events = stream::select(
+        setting_results.map(Either::Left),
+        readings.map(Either::Right),
+    );
+    futures::pin_mut!(events);
+    while !info.is_complete() {
+        match events.next().await {
+            Some(Either::Left(SettingResult::BatteryLevel {
+                current_voltage,
+                max_voltage,
+            })) => {
+                info.current_voltage = Some(current_voltage);
+                info.max_voltage = Some(max_voltage);
+            }
+            Some(Either::Left(_)) => {}
+            Some(Either::Right(reading)) => {
+                info.probe_count = Some(reading.probe_temperatures.len());
+            }
+            None => break,
+        }
+    }
+}
+
+fn check_targets(targets: &HashMap<u8, Range<f32>>, probe_count: usize) -> Result<(), Error> {
+    match targets
+        .keys()
+        .filter(|&&probe| usize::from(probe) >= probe_count)
+        .min()
+    {
+        Some(&probe) => Err(Error::NoSuchProbe { probe, probe_count }),
+        None => Ok(()),
+    }
+}
+
+/// Format a description of the given characteristics, one per line.
+fn describe_characteristics(
+    characteristics: &[CharacteristicInfo],
+    notifying: &HashSet<CharacteristicId>,
+) -> String {
+    let mut description = format!("BBQ service {}:\n", BBQ_SERVICE_UUID.succinctly());
+    for characteristic in characteristics {
+        let name = match characteristic.uuid {
+            SETTING_RESULT_UUID => "setting result",
+            ACCOUNT_AND_VERIFY_UUID => "account and verify",
+            HISTORY_DATA_UUID => "history data",
+            REAL_TIME_DATA_UUID => "real time data",
+            SETTING_DATA_UUID => "setting data",
+            _ => "unknown",
+        };
+        let mut properties = vec![];
+        if characteristic.flags.contains(CharacteristicFlags::READ) {
+            properties.push("read");
+        }
+        if characteristic
+            .flags
+            .intersects(CharacteristicFlags::WRITE | CharacteristicFlags::WRITE_WITHOUT_RESPONSE)
+        {
+            properties.push("write");
+        }
+        if characteristic.flags.contains(CharacteristicFlags::NOTIFY) {
+            properties.push("notify");
+        }
+        if notifying.contains(&characteristic.id) {
+            properties.push("notifying");
+        }
+        description += &format!(
+            "  {} ({}): {}\n",
+            characteristic.uuid.succinctly(),
+            name,
+            properties.join(", ")
+        );
+    }
+    description
+}
+
+fn probe_states(
+    reading: &RealTimeData,
+    targets: &HashMap<u8, Range<f32>>,
+    labels: &ProbeLabels,
+    colors: &HashMap<u8, Color>,
+) -> Vec<ProbeState> {
+    reading
+        .probe_temperatures
+        .iter()
+        .zip(0..=u8::MAX)
+        .map(|(temperature, index)| ProbeState {
+            index,
+            temperature: *temperature,
+            target: targets.get(&index).cloned(),
+            label: labels.get(index).map(ToOwned::to_owned),
+            color: colors.get(&index).copied(),
+        })
+        .collect()
+}
+
+/// Return whether the given temperature is inside the given target range. A bound meaning no alarm
+/// on that side doesn't limit the range, so e.g. a low-only target above `TARGET_TEMP_NO_MAXIMUM`
+/// contains everything above its low alarm.
+pub(crate) fn target_contains(range: &Range<f32>, temperature: f32) -> bool {
+    (range.start == TARGET_TEMP_NO_MINIMUM || temperature >= range.start)
+        && (range.end == TARGET_TEMP_NO_MAXIMUM || temperature < range.end)
+}
+
+/// Return the indices of the probes in the given reading whose temperatures are outside their
+/// targets. Disconnected probes and probes with no target are never alarming.
+fn alarming_probes(reading: &RealTimeData, targets: &HashMap<u8, Range<f32>>) -> Vec<u8> {
+    reading
+        .probe_temperatures
+        .iter()
+        .zip(0..=u8::MAX)
+        .filter_map(
+            |(temperature, index)| match (temperature, targets.get(&index)) {
+                (Some(temperature), Some(range)) if !target_contains(range, *temperature) => {
+                    Some(index)
+                }
+                _ => None,
+            },
+        )
+        .collect()
+}
+
+/// Filter the given events to disconnections of the given device.
+fn disconnections(
+    events: impl Stream<Item = BluetoothEvent>,
+    device_id: DeviceId,
+) -> impl Stream<Item = ()> {
+    events.filter_map(move |event| {
+        future::ready(match event {
+            BluetoothEvent::Device {
+                id,
+                event: DeviceEvent::Connected { connected: false },
+            } if id == device_id => Some(()),
+            _ => None,
+        })
+    })
+}
+
+/// Reassemble the given history notifications into records of the given length in bytes, or one per
+/// notification if the length isn't known, ending at the first empty notification.
+fn history_records(
+    frames: impl Stream<Item = Vec<u8>>,
+    record_length: Option<usize>,
+    byte_order: ByteOrder,
+) -> impl Stream<Item = HistoryData> {
+    frames
+        .take_while(|frame| future::ready(!frame.is_empty()))
+        .scan(Vec::new(), move |buffer: &mut Vec<u8>, frame| {
+            buffer.extend_from_slice(&frame);
+            // The buffer can't be empty, as empty frames end the stream.
+            let record_length = record_length.unwrap_or(buffer.len());
+            let complete = buffer.len() - buffer.len() % record_length;
+            let records: Vec<_> = buffer
+                .drain(..complete)
+                .collect::<Vec<_>>()
+                .chunks(record_length)
+                .filter_map(|record| {
+                    let parsed = HistoryData::try_parse(record, byte_order);
+                    if parsed.is_none() {
+                        info!("Invalid history record: {:?}", record);
+                    }
+                    parsed
+                })
+                .collect();
+            future::ready(Some(stream::iter(records)))
+        })
+        .flatten()
+}
+
+/// Write the given command with `write` while holding `write_lock`, unless it is dropped by the
+/// command throttle in the given state, then apply `record` to the state if it succeeded.
+async fn serialized_write<F: Future<Output = Result<(), BluetoothError>>>(
+    write_lock: &tokio::sync::Mutex<()>,
+    state: &Mutex<DeviceState>,
+    command: Vec<u8>,
+    record: impl FnOnce(&mut DeviceState),
+    write: impl FnOnce(Vec<u8>) -> F,
+) -> Result<(), BluetoothError> {
+    let _guard = write_lock.lock().await;
+    let now = Instant::now();
+    if state.lock().unwrap().should_write(&command, now) {
+        // Only record the command once it's written, so that a retry after a failure isn't dropped.
+        write(command.clone()).await?;
+        state.lock().unwrap().record_command(command, now);
+    } else {
+        info!("Dropping repeated command {:?}", command);
+    }
+    record(&mut state.lock().unwrap());
+    Ok(())
+}
+
+/// Filter the given events to the values of the given characteristic, logging each if raw frame
+/// logging is enabled in the given state.
+///
+/// Only a weak reference to the state is kept, as the stream may be owned by a task which the state
+/// owns in turn, such as the acknowledgement dispatcher.
+fn characteristic_values(
+    events: impl Stream<Item = BluetoothEvent>,
+    characteristic: CharacteristicId,
+    name: &'static str,
+    uuid: Uuid,
+    state: Weak<Mutex<DeviceState>>,
+) -> impl Stream<Item = Vec<u8>> {
+    characteristic_values_logging_to(events, characteristic, name, uuid, state, |message| {
+        debug!("{}", message)
+    })
+}
+
+/// Like `characteristic_values`, but passing raw frame messages to `log` rather than the logger.
+fn characteristic_values_logging_to(
+    events: impl Stream<Item = BluetoothEvent>,
+    characteristic: CharacteristicId,
+    name: &'static str,
+    uuid: Uuid,
+    state: Weak<Mutex<DeviceState>>,
+    log: impl Fn(String),
+) -> impl Stream<Item = Vec<u8>> {
+    StreamExt::filter_map(events, move |event| {
+        future::ready(match event {
+            BluetoothEvent::Characteristic {
+                id,
+                event: CharacteristicEvent::Value { value },
+            } if id == characteristic => {
+                let enabled = state
+                    .upgrade()
+                    .is_some_and(|state| state.lock().unwrap().log_raw_frames);
+                if enabled {
+                    log(raw_frame_message(name, uuid, &value));
+                }
+                Some(value)
+            }
+            _ => {
+                info!("Unexpected Bluetooth event {:?}", event);
+                None
+            }
+        })
+    })
+}
+
+/// Parse the setting results from the given events for the given setting result characteristic.
+fn setting_results(
+    events: impl Stream<Item = BluetoothEvent>,
+    characteristic: CharacteristicId,
+    state: Weak<Mutex<DeviceState>>,
+) -> impl Stream<Item = SettingResult> {
+    characteristic_values(
+        events,
+        characteristic,
+        "setting result",
+        SETTING_RESULT_UUID,
+        state,
+    )
+    .filter_map(|value| future::ready(SettingResult::try_parse(&value)))
+}
+
+/// Format the message logged for a raw frame from the characteristic with the given name and UUID.
+fn raw_frame_message(name: &str, uuid: Uuid, value: &[u8]) -> String {
+    let hex = value
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!("Raw {} frame ({}): {}", name, uuid.succinctly(), hex)
+}
+
+/// Mark the given state as needing authentication whenever the given device connects or disconnects.
+/// Stops at the first such event after the state has been dropped, if not aborted before then.
+async fn track_connection(
+    events: impl Stream<Item = BluetoothEvent>,
+    device_id: DeviceId,
+    state: Weak<Mutex<DeviceState>>,
+) {
+    futures::pin_mut!(events);
+    while let Some(event) = events.next().await {
+        if let BluetoothEvent::Device {
+            id,
+            event: DeviceEvent::Connected { connected },
+        } = event
+        {
+            if id == device_id {
+                let state = match state.upgrade() {
+                    Some(state) => state,
+                    // The device has been dropped, so there is nobody to tell.
+                    None => return,
+                };
+                info!(
+                    "Device connected changed to {}, needs authentication",
+                    connected
+                );
+                state.lock().unwrap().authenticated = false;
+            }
+        }
+    }
+}
+
+/// Wait for the first reading in which the given probe is at or above the target temperature.
+async fn wait_for_temperature(
+    readings: impl Stream<Item = RealTimeData>,
+    probe: u8,
+    target: f32,
+) -> Result<(), Error> {
+    futures::pin_mut!(readings);
+    while let Some(reading) = readings.next().await {
+        match reading.probe_temperatures.get(usize::from(probe)) {
+            Some(Some(temperature)) if *temperature >= target => return Ok(()),
+            Some(Some(_)) => {}
+            _ => return Err(Error::ProbeDisconnected(probe)),
+        }
+    }
+    Err(Error::StreamEnded)
+}
+
+/// Map setting results and readings to alarm events, comparing readings against the targets and
+/// safety ceilings in the given state. Events are only produced when a probe crosses a threshold,
+/// not for every reading outside the range.
+fn alarm_events(
+    events: impl Stream<Item = Either<SettingResult, RealTimeData>>,
+    state: Arc<Mutex<DeviceState>>,
+) -> impl Stream<Item = AlarmEvent> {
+    events
+        .scan(
+            (HashSet::<u8>::new(), HashSet::<u8>::new()),
+            move |(alarming, over_ceiling), event| {
+                let alarms = match event {
+                    Either::Left(SettingResult::SilencePressed { probe }) => {
+                        vec![AlarmEvent::DeviceAlarmSilenced { probe }]
+                    }
+                    Either::Left(_) => vec![],
+                    Either::Right(reading) => {
+                        let (targets, ceilings) = {
+                            let state = state.lock().unwrap();
+                            (state.targets.clone(), state.safety_ceilings.clone())
+                        };
+                        let mut alarms = vec![];
+                        for (temperature, probe) in
+                            reading.probe_temperatures.iter().zip(0..=u8::MAX)
+                        {
+                            let (temperature, ceiling) = match (temperature, ceilings.get(&probe)) {
+                                (Some(temperature), Some(ceiling)) => (*temperature, *ceiling),
+                                _ => {
+                                    over_ceiling.remove(&probe);
+                                    continue;
+                                }
+                            };
+                            if temperature <= ceiling {
+                                if over_ceiling.remove(&probe) {
+                                    alarms.push(AlarmEvent::SafetyCeilingCleared {
+                                        probe,
+                                        temperature,
+                                        ceiling,
+                                    });
+                                }
+                            } else if over_ceiling.insert(probe) {
+                                alarms.push(AlarmEvent::SafetyCeilingExceeded {
+                                    probe,
+                                    temperature,
+                                    ceiling,
+                                });
+                            }
+                        }
+                        for (temperature, probe) in
+                            reading.probe_temperatures.iter().zip(0..=u8::MAX)
+                        {
+                            let (temperature, range) = match (temperature, targets.get(&probe)) {
+                                (Some(temperature), Some(range)) => (*temperature, range.clone()),
+                                // Forget about probes which are disconnected or have no target.
+                                _ => {
+                                    alarming.remove(&probe);
+                                    continue;
+                                }
+                            };
+                            if target_contains(&range, temperature) {
+                                if alarming.remove(&probe) {
+                                    alarms.push(AlarmEvent::SoftwareThresholdCleared {
+                                        probe,
+                                        temperature,
+                                        range,
+                                    });
+                                }
+                            } else if alarming.insert(probe) {
+                                alarms.push(AlarmEvent::SoftwareThresholdCrossed {
+                                    probe,
+                                    temperature,
+                                    range,
+                                });
+                            }
+                        }
+                        alarms
+                    }
+                };
+                future::ready(Some(stream::iter(alarms)))
+            },
+        )
+        .flatten()
+}
+
+/// Parse the given raw real-time data frames with the byte order and offsets in the given state,
+/// discarding any before authentication and recording the rest in the state.
+fn real_time_readings(
+    frames: impl Stream<Item = Vec<u8>>,
+    state: Arc<Mutex<DeviceState>>,
+) -> impl Stream<Item = RealTimeData> {
+    let parse_state = state.clone();
+    let readings = StreamExt::filter_map(frames, move |value| {
+        let state = parse_state.lock().unwrap();
+        future::ready(
+            RealTimeData::try_parse(&value, state.profile.byte_order)
+                .map(|reading| state.apply_offsets(reading)),
+        )
+    });
+    authenticated_only(readings, state.clone()).inspect(move |reading| {
+        let mut state = state.lock().unwrap();
+        state.record_reading(reading.clone(), Instant::now());
+        state.buffer_reading(SystemTime::now(), reading.clone());
+    })
+}
+
+/// Discard any readings which arrive before the device has been authenticated.
+fn authenticated_only(
+    readings: impl Stream<Item = RealTimeData>,
+    state: Arc<Mutex<DeviceState>>,
+) -> impl Stream<Item = RealTimeData> {
+    readings.filter(move |reading| {
+        let authenticated = state.lock().unwrap().authenticated;
+        if !authenticated {
+            info!(
+                "Discarding real-time data before authentication: {:?}",
+                reading
+            );
+        }
+        future::ready(authenticated)
+    })
+}
+
+/// Return the byte order for temperatures in the given real-time data frames: the current one,
+/// unless the other one gives plausible temperatures for all of them and the current one doesn't.
+fn infer_byte_order(frames: &[Vec<u8>], current: ByteOrder) -> ByteOrder {
+    let plausible = |byte_order| {
+        frames.iter().all(|frame| {
+            RealTimeData::try_parse(frame, byte_order).map_or(true, |reading| {
+                reading
+                    .probe_temperatures
+                    .iter()
+                    .flatten()
+                    .all(|temperature| PLAUSIBLE_TEMPERATURE_RANGE.contains(temperature))
+            })
+        })
+    };
+    let other = match current {
+        ByteOrder::LittleEndian => ByteOrder::BigEndian,
+        ByteOrder::BigEndian => ByteOrder::LittleEndian,
+    };
+    if !plausible(current) && plausible(other) {
+        other
+    } else {
+        current
+    }
+}
+
+fn encode_temperature(temperature: f32, byte_order: ByteOrder) -> Result<[u8; 2], Error> {
+    if !(TEMPERATURE_MIN..=TEMPERATURE_MAX).contains(&temperature) {
+        return Err(Error::TemperatureEncodingError(temperature));
+    }
+    // Round rather than truncate, as e.g. 51.3 * 10.0 is slightly less than 513 in floating point.
+    // The range check above ensures that this can't overflow.
+    let temperature_fixed = (temperature * 10.0).round() as i16;
+    Ok(match byte_order {
+        ByteOrder::LittleEndian => temperature_fixed.to_le_bytes(),
+        ByteOrder::BigEndian => temperature_fixed.to_be_bytes(),
+    })
+}
+
+fn decode_temperature(bytes: [u8; 2], byte_order: ByteOrder) -> f32 {
+    let temperature_fixed = match byte_order {
+        ByteOrder::LittleEndian => i16::from_le_bytes(bytes),
+        ByteOrder::BigEndian => i16::from_be_bytes(bytes),
+    };
+    temperature_fixed as f32 / 10.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bluez_async::AddressType;
+    use futures::executor::block_on;
+    use futures::stream;
+
+    #[test]
+    fn encode_temperature_limits() {
+        assert_eq!(
+            encode_temperature(TEMPERATURE_MAX, ByteOrder::LittleEndian).unwrap(),
+            i16::MAX.to_le_bytes()
+        );
+        assert_eq!(
+            encode_temperature(TEMPERATURE_MIN, ByteOrder::LittleEndian).unwrap(),
+            i16::MIN.to_le_bytes()
+        );
+        for temperature in [TEMPERATURE_MAX, TEMPERATURE_MIN, 51.3, -0.1] {
+            let bytes = encode_temperature(temperature, ByteOrder::LittleEndian).unwrap();
+            assert_eq!(
+                decode_temperature(bytes, ByteOrder::LittleEndian),
+                temperature
+            );
+        }
+    }
+
+    #[test]
+    fn parse_big_endian() {
+        let expected = RealTimeData {
+            probe_temperatures: vec![Some(51.3), None],
+        };
+        assert_eq!(
+            RealTimeData::try_parse(
+                &[0x01, 0x02, 0xF6, 0xFF],
+                DeviceProfile::STANDARD.byte_order
+            ),
+            Some(expected.clone())
+        );
+        assert_eq!(
+            RealTimeData::try_parse(
+                &[0x02, 0x01, 0xFF, 0xF6],
+                DeviceProfile::TEST_CLONE.byte_order
+            ),
+            Some(expected)
+        );
+    }
+
+    #[test]
+    fn infer_big_endian() {
+        // 0.0°C and 25.7°C, which are the same in either byte order.
+        let ambiguous = vec![vec![0x00, 0x00, 0x01, 0x01], vec![0x01, 0x01, 0x00, 0x00]];
+        assert_eq!(
+            infer_byte_order(&ambiguous, ByteOrder::LittleEndian),
+            ByteOrder::LittleEndian
+        );
+        // 22.5°C in big-endian order, which would be -793.6°C in little-endian.
+        let mut frames = ambiguous.clone();
+        frames.push(vec![0x00, 0xE1, 0xFF, 0xF6]);
+        assert_eq!(
+            infer_byte_order(&frames, ByteOrder::LittleEndian),
+            ByteOrder::BigEndian
+        );
+        assert_eq!(
+            infer_byte_order(&frames, ByteOrder::BigEndian),
+            ByteOrder::BigEndian
+        );
+    }
+
+    #[test]
+    fn infer_implausible_either_way() {
+        // 0x80 0x80 is about -3264°C either way round, so there's no reason to switch.
+        let frames = vec![vec![0x80, 0x80]];
+        assert_eq!(
+            infer_byte_order(&frames, ByteOrder::LittleEndian),
+            ByteOrder::LittleEndian
+        );
+    }
+
+    #[test]
+    fn encode_fahrenheit_target() {
+        let encode = |target, unit: TemperatureUnit| {
+            encode_temperature(unit.convert_to_celsius(target), ByteOrder::LittleEndian)
+        };
+        assert_eq!(
+            encode(212.0, TemperatureUnit::Fahrenheit).unwrap(),
+            1000i16.to_le_bytes()
+        );
+        assert_eq!(
+            encode(-40.0, TemperatureUnit::Fahrenheit).unwrap(),
+            (-400i16).to_le_bytes()
+        );
+        // The limit of 3276.7°C is about 5930.1°F.
+        assert!(encode(5930.0, TemperatureUnit::Fahrenheit).is_ok());
+        assert!(matches!(
+            encode(5932.0, TemperatureUnit::Fahrenheit),
+            Err(Error::TemperatureEncodingError(_))
+        ));
+        assert!(matches!(
+            encode(TEMPERATURE_MAX + 1.0, TemperatureUnit::Celcius),
+            Err(Error::TemperatureEncodingError(_))
+        ));
+    }
+
+    #[test]
+    fn encode_temperature_out_of_range() {
+        for temperature in [TEMPERATURE_MAX + 0.1, TEMPERATURE_MIN - 0.1, f32::NAN] {
+            assert!(matches!(
+                encode_temperature(temperature, ByteOrder::LittleEndian),
+                Err(Error::TemperatureEncodingError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn parse_real_time_invalid() {
+        assert_eq!(RealTimeData::try_parse(&[0], ByteOrder::LittleEndian), None);
+    }
+
+    #[test]
+    fn parse_real_time_no_probes() {
+        assert_eq!(
+            RealTimeData::try_parse(&[0xF6, 0xFF, 0xF6, 0xFF], ByteOrder::LittleEndian),
+            Some(RealTimeData {
+                probe_temperatures: vec![None, None]
+            })
+        );
+    }
+
+    #[test]
+    fn parse_real_time() {
+        assert_eq!(
+            RealTimeData::try_parse(&[1, 2, 3, 4], ByteOrder::LittleEndian),
+            Some(RealTimeData {
+                probe_temperatures: vec![Some(51.3), Some(102.7)]
+            })
+        );
+    }
+
+    #[test]
+    fn typed_reading_units() {
+        let readings = stream::iter(vec![
+            RealTimeData::try_parse(&[0xE8, 0x03, 0xF6, 0xFF], ByteOrder::LittleEndian).unwrap(),
+            RealTimeData::try_parse(&[0x00, 0x00, 0xF6, 0xFF], ByteOrder::LittleEndian).unwrap(),
+        ]);
+        let typed: Vec<_> = block_on(readings.map(RealTimeReading::from).collect());
+        assert_eq!(
+            typed[0].probes,
+            vec![Some(Temperature::from_celsius(100.0)), None]
+        );
+        assert!(typed[0].is_connected(0));
+        assert!(!typed[0].is_connected(1));
+        assert!(!typed[0].is_connected(2));
+        let boiling = typed[0].probes[0].unwrap();
+        assert_eq!(boiling.celsius(), 100.0);
+        assert_eq!(boiling.fahrenheit(), 212.0);
+        let freezing = typed[1].probes[0].unwrap();
+        assert_eq!(freezing.in_unit(TemperatureUnit::Fahrenheit), 32.0);
+        assert_eq!(
+            Temperature::from_unit(212.0, TemperatureUnit::Fahrenheit),
+            boiling
+        );
+    }
+
+    #[test]
+    fn fingerprint_equal_readings() {
+        let data =
+            RealTimeData::try_parse(&[0x01, 0x02, 0xF6, 0xFF], ByteOrder::LittleEndian).unwrap();
+        assert_eq!(data.fingerprint(), vec![Some(513), None]);
+        let same = RealTimeData {
+            probe_temperatures: vec![Some(51.3), None],
+        };
+        assert_eq!(data.fingerprint(), same.fingerprint());
+        let mut set = HashSet::new();
+        set.insert(data.fingerprint());
+        assert!(!set.insert(same.fingerprint()));
+    }
+
+    #[test]
+    fn connected_map_skips_disconnected() {
+        let data = RealTimeData {
+            probe_temperatures: vec![None, Some(21.5), None, Some(80.0)],
+        };
+        let mut expected = HashMap::new();
+        expected.insert(1, 21.5);
+        expected.insert(3, 80.0);
+        assert_eq!(data.connected_map(), expected);
+        assert_eq!(data.probe_connected(), vec![false, true, false, true]);
+    }
+
+    #[test]
+    fn apply_offsets() {
+        let mut state = DeviceState {
+            global_offset: -2.0,
+            ..Default::default()
+        };
+        state.probe_offsets.insert(0, 1.5);
+        let reading = RealTimeData {
+            probe_temperatures: vec![Some(20.0), Some(20.0), None],
+        };
+        assert_eq!(
+            state.apply_offsets(reading.clone()),
+            RealTimeData {
+                probe_temperatures: vec![Some(21.5), Some(18.0), None],
+            }
+        );
+        state.probe_offsets.clear();
+        state.global_offset = 0.0;
+        assert_eq!(state.apply_offsets(reading.clone()), reading);
+    }
+
+    #[test]
+    fn pause_and_resume_real_time() {
+        let mut state = DeviceState {
+            real_time: RealTimeState::Enabled,
+            ..Default::default()
+        };
+        assert!(state.pause_real_time());
+        assert_eq!(state.real_time, RealTimeState::Paused);
+        assert!(!state.pause_real_time());
+        assert_eq!(state.real_time, RealTimeState::Paused);
+        assert!(state.resume_real_time());
+        assert_eq!(state.real_time, RealTimeState::Enabled);
+        assert!(!state.resume_real_time());
+        assert_eq!(state.real_time, RealTimeState::Enabled);
+    }
+
+    #[test]
+    fn pause_real_time_when_disabled() {
+        let mut state = DeviceState::default();
+        assert!(!state.pause_real_time());
+        assert_eq!(state.real_time, RealTimeState::Disabled);
+        assert!(!state.resume_real_time());
+        assert_eq!(state.real_time, RealTimeState::Disabled);
+    }
+
+    #[test]
+    fn throttle_drops_repeated_commands() {
+        let mut state = DeviceState {
+            throttle: Some(CommandThrottle {
+                window: Duration::from_secs(1),
+            }),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut write = |command: &[u8], time| {
+            let should_write = state.should_write(command, time);
+            if should_write {
+                state.record_command(command.to_owned(), time);
+            }
+            should_write
+        };
+        let (a, b) = ([0x01, 0x00], [0x01, 0x01]);
+        assert!(write(&a, at(0)));
+        assert!(!write(&a, at(100)));
+        assert!(!write(&a, at(900)));
+        assert!(write(&a, at(1000)));
+        assert!(write(&b, at(1100)));
+        assert!(write(&a, at(1200)));
+    }
+
+    #[tokio::test]
+    async fn retry_after_failed_write() {
+        let write_lock = tokio::sync::Mutex::new(());
+        let state = Mutex::new(DeviceState {
+            throttle: Some(CommandThrottle {
+                window: Duration::from_secs(60),
+            }),
+            ..Default::default()
+        });
+        let attempts = Mutex::new(0);
+        let write = |succeed| {
+            let attempts = &attempts;
+            move |_| async move {
+                *attempts.lock().unwrap() += 1;
+                if succeed {
+                    Ok(())
+                } else {
+                    Err(BluetoothError::NoBluetoothAdapters)
+                }
+            }
+        };
+
+        let command = vec![0x01, 0x00];
+        let result = serialized_write(&write_lock, &state, command.clone(), |_| {}, write(false));
+        assert!(result.await.is_err());
+        let result = serialized_write(&write_lock, &state, command.clone(), |_| {}, write(true));
+        result.await.unwrap();
+        assert_eq!(*attempts.lock().unwrap(), 2);
+
+        // Now that it has been written, a repeat is dropped.
+        let result = serialized_write(&write_lock, &state, command, |_| {}, write(true));
+        result.await.unwrap();
+        assert_eq!(*attempts.lock().unwrap(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_target_writes() {
+        let write_lock = Arc::new(tokio::sync::Mutex::new(()));
+        let state = Arc::new(Mutex::new(DeviceState::default()));
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let writes: Vec<_> = (0..20u8)
+            .map(|i| {
+                let (write_lock, state, written) =
+                    (write_lock.clone(), state.clone(), written.clone());
+                let target = f32::from(i) * 10.0;
+                tokio::spawn(async move {
+                    let command = command_bytes::set_target_range(
+                        &DeviceProfile::default(),
+                        0,
+                        TARGET_TEMP_NO_MINIMUM..target,
+                    )
+                    .unwrap();
+                    serialized_write(
+                        &write_lock,
+                        &state,
+                        command,
+                        |state| state.record_target(0, TARGET_TEMP_NO_MINIMUM..target),
+                        |command| async move {
+                            tokio::task::yield_now().await;
+                            written.lock().unwrap().push((command, target));
+                            Ok(())
+                        },
+                    )
+                    .await
+                })
+            })
+            .collect();
+        for write in writes {
+            write.await.unwrap().unwrap();
+        }
+
+        let written = written.lock().unwrap();
+        assert_eq!(written.len(), 20);
+        let (_, last_target) = written.last().unwrap();
+        assert_eq!(
+            state.lock().unwrap().targets.get(&0),
+            Some(&(TARGET_TEMP_NO_MINIMUM..*last_target))
+        );
+    }
+
+    #[test]
+    fn no_throttle_by_default() {
+        let mut state = DeviceState::default();
+        let now = Instant::now();
+        assert!(state.should_write(&[0x01], now));
+        state.record_command(vec![0x01], now);
+        assert!(state.should_write(&[0x01], now));
+    }
+
+    #[test]
+    fn set_and_remove_color() {
+        let mut state = DeviceState::default();
+        state.set_color(0, Some(Color::rgb(0xFF, 0x80, 0x00)));
+        state.set_color(1, Some(Color::rgb(0x00, 0x00, 0xFF)));
+        state.set_color(1, None);
+        assert_eq!(state.colors.get(&0), Some(&Color::rgb(0xFF, 0x80, 0x00)));
+        assert_eq!(state.colors.get(&1), None);
+    }
+
+    #[test]
+    fn history_buffer_wraparound() {
+        let mut state = DeviceState::default();
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let all_readings = readings(&[Some(1.0), Some(2.0), Some(3.0), Some(4.0), Some(5.0)]);
+
+        // Disabled by default.
+        state.buffer_reading(at(0), all_readings[0].clone());
+        assert!(state.recent_readings.is_empty());
+
+        state.set_recent_readings_capacity(3);
+        for (i, reading) in all_readings.iter().enumerate() {
+            state.buffer_reading(at(i as u64), reading.clone());
+        }
+        assert_eq!(
+            state.recent_readings,
+            vec![
+                (at(2), all_readings[2].clone()),
+                (at(3), all_readings[3].clone()),
+                (at(4), all_readings[4].clone()),
+            ]
+        );
+
+        state.set_recent_readings_capacity(1);
+        assert_eq!(
+            state.recent_readings,
+            vec![(at(4), all_readings[4].clone())]
+        );
+        state.set_recent_readings_capacity(0);
+        assert!(state.recent_readings.is_empty());
+    }
+
+    #[test]
+    fn last_reading_age() {
+        let mut state = DeviceState::default();
+        let start = Instant::now();
+        assert_eq!(state.last_reading_age(start), None);
+        state.record_reading(readings(&[Some(50.0)]).remove(0), start);
+        assert_eq!(
+            state.last_reading_age(start + Duration::from_secs(45)),
+            Some(Duration::from_secs(45))
+        );
+        assert_eq!(
+            state.last_reading.unwrap(),
+            readings(&[Some(50.0)]).remove(0)
+        );
+    }
+
+    #[test]
+    fn record_and_remove_target() {
+        let mut state = DeviceState::default();
+        state.record_target(0, TARGET_TEMP_NO_MINIMUM..70.0);
+        state.record_target(1, 100.0..120.0);
+        state.record_target(0, TARGET_TEMP_NO_MINIMUM..TARGET_TEMP_NO_MAXIMUM);
+        let mut expected = HashMap::new();
+        expected.insert(1, 100.0..120.0);
+        assert_eq!(state.targets, expected);
+        state.record_target(2, 60.0..TARGET_TEMP_NO_MAXIMUM);
+        state.record_target(1, 90.0..120.0);
+        // This is what `BBQDevice::active_alarm_count` returns.
+        assert_eq!(state.targets.len(), 2);
+    }
+
+    #[test]
+    fn alarming_probes_outside_targets() {
+        let reading = RealTimeData {
+            probe_temperatures: vec![Some(75.0), None, Some(110.0), Some(90.0), Some(50.0)],
+        };
+        let mut targets = HashMap::new();
+        targets.insert(0, TARGET_TEMP_NO_MINIMUM..70.0);
+        targets.insert(1, TARGET_TEMP_NO_MINIMUM..70.0);
+        targets.insert(2, 100.0..120.0);
+        targets.insert(4, 60.0..TARGET_TEMP_NO_MAXIMUM);
+        assert_eq!(alarming_probes(&reading, &targets), vec![0, 4]);
+    }
+
+    #[test]
+    fn low_only_target_above_no_maximum() {
+        let reading = RealTimeData {
+            probe_temperatures: vec![Some(350.0), Some(500.0), Some(200.0)],
+        };
+        let targets = (0..3)
+            .map(|probe| (probe, 320.0..TARGET_TEMP_NO_MAXIMUM))
+            .collect();
+        assert_eq!(alarming_probes(&reading, &targets), vec![2]);
+    }
+
+    #[test]
+    fn probe_states_snapshot() {
+        let reading = RealTimeData {
+            probe_temperatures: vec![Some(65.2), None, Some(110.0)],
+        };
+        let mut targets = HashMap::new();
+        targets.insert(0, TARGET_TEMP_NO_MINIMUM..70.0);
+        targets.insert(2, 100.0..120.0);
+        let mut labels = ProbeLabels::default();
+        labels.set(0, "brisket");
+        labels.set(2, "chamber");
+        let mut colors = HashMap::new();
+        colors.insert(0, Color::rgb(0xFF, 0x00, 0x00));
+        assert_eq!(
+            probe_states(&reading, &targets, &labels, &colors),
+            vec![
+                ProbeState {
+                    index: 0,
+                    temperature: Some(65.2),
+                    target: Some(TARGET_TEMP_NO_MINIMUM..70.0),
+                    label: Some("brisket".to_owned()),
+                    color: Some(Color::rgb(0xFF, 0x00, 0x00)),
+                },
+                ProbeState {
+                    index: 1,
+                    temperature: None,
+                    target: None,
+                    label: None,
+                    color: None,
+                },
+                ProbeState {
+                    index: 2,
+                    temperature: Some(110.0),
+                    target: Some(100.0..120.0),
+                    label: Some("chamber".to_owned()),
+                    color: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn format_with_labels() {
+        let data = RealTimeData {
+            probe_temperatures: vec![Some(51.3), None, Some(20.0)],
+        };
+        let mut labels = ProbeLabels::default();
+        labels.set(0, "brisket");
+        labels.set(1, "ambient");
+        assert_eq!(
+            data.format_with_labels(&labels),
+            "brisket: 51.3°C, ambient: disconnected, Probe 2: 20.0°C"
+        );
+    }
+
+    #[test]
+    fn format_without_labels() {
+        let data = RealTimeData {
+            probe_temperatures: vec![None, Some(102.7)],
+        };
+        assert_eq!(
+            data.format_with_labels(&ProbeLabels::default()),
+            "Probe 0: disconnected, Probe 1: 102.7°C"
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn real_time_data_serde_round_trip() {
+        let data = RealTimeData {
+            probe_temperatures: vec![Some(51.3), None],
+        };
+        let json = serde_json::to_string(&data).unwrap();
+        assert_eq!(json, r#"{"probe_temperatures":[51.3,null]}"#);
+        assert_eq!(serde_json::from_str::<RealTimeData>(&json).unwrap(), data);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn setting_result_serde_round_trip() {
+        let battery = SettingResult::BatteryLevel {
+            current_voltage: 5979,
+            max_voltage: 6550,
+        };
+        let json = serde_json::to_string(&battery).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"BatteryLevel","current_voltage":5979,"max_voltage":6550}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<SettingResult>(&json).unwrap(),
+            battery
+        );
+        for result in [
+            SettingResult::AcknowledgeCommand {
+                command_id: SET_UNIT_COMMAND,
+                success: false,
+                status: AckStatus::Unknown([0x07, 0x01, 0x00, 0x00]),
+            },
+            SettingResult::SilencePressed { probe: Some(2) },
+        ] {
+            let json = serde_json::to_string(&result).unwrap();
+            assert_eq!(
+                serde_json::from_str::<SettingResult>(&json).unwrap(),
+                result
+            );
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn probe_labels_serde_round_trip() {
+        let mut labels = ProbeLabels::default();
+        labels.set(0, "brisket");
+        labels.set(3, "ambient");
+        let json = serde_json::to_string(&labels).unwrap();
+        assert_eq!(serde_json::from_str::<ProbeLabels>(&json).unwrap(), labels);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn device_snapshot_serde_round_trip() {
+        let mut snapshot = DeviceSnapshot {
+            unit: Some(TemperatureUnit::Fahrenheit),
+            last_reading: Some(RealTimeData {
+                probe_temperatures: vec![Some(65.2), None],
+            }),
+            ..Default::default()
+        };
+        snapshot.targets.insert(0, TARGET_TEMP_NO_MINIMUM..70.0);
+        snapshot.targets.insert(1, 100.0..120.0);
+        snapshot.labels.set(0, "brisket");
+        snapshot.colors.insert(0, Color::rgb(0x12, 0x34, 0x56));
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert_eq!(
+            serde_json::from_str::<DeviceSnapshot>(&json).unwrap(),
+            snapshot
+        );
+    }
+
+    #[test]
+    fn parse_temperature_unit() {
+        for s in [
+            "c", "C", "celsius", "Celsius", "CELSIUS", "celcius", "Celcius",
+        ] {
+            assert_eq!(s.parse(), Ok(TemperatureUnit::Celcius));
+        }
+        for s in ["f", "F", "fahrenheit", "Fahrenheit", "FAHRENHEIT"] {
+            assert_eq!(s.parse(), Ok(TemperatureUnit::Fahrenheit));
+        }
+    }
+
+    #[test]
+    fn parse_temperature_unit_invalid() {
+        assert_eq!(
+            "kelvin".parse::<TemperatureUnit>(),
+            Err(ParseTemperatureUnitError("kelvin".to_owned()))
+        );
+    }
+
+    #[test]
+    fn convert_temperature_unit() {
+        assert_eq!(TemperatureUnit::Celcius.convert_from_celsius(42.0), 42.0);
+        assert_eq!(TemperatureUnit::Fahrenheit.convert_from_celsius(0.0), 32.0);
+        assert_eq!(
+            TemperatureUnit::Fahrenheit.convert_from_celsius(100.0),
+            212.0
+        );
+        assert_eq!(TemperatureUnit::Celcius.convert_to_celsius(42.0), 42.0);
+        assert_eq!(TemperatureUnit::Fahrenheit.convert_to_celsius(212.0), 100.0);
+    }
+
+    #[test]
+    fn real_time_data_is_celsius_regardless_of_display_unit() {
+        // A reading of 104.4ºC. Setting the display to Fahrenheit doesn't change the frames the
+        // device sends, so this must still decode as Celcius.
+        let state = Arc::new(Mutex::new(DeviceState {
+            unit: Some(TemperatureUnit::Fahrenheit),
+            authenticated: true,
+            ..Default::default()
+        }));
+        let frames = stream::iter(vec![vec![0x14, 0x04]]);
+        let readings: Vec<_> = block_on(real_time_readings(frames, state).collect());
+        assert_eq!(
+            readings,
+            vec![RealTimeData {
+                probe_temperatures: vec![Some(104.4)],
+            }]
+        );
+        let fahrenheit = TemperatureUnit::Fahrenheit.convert_from_celsius(104.4);
+        assert!((fahrenheit - 219.92).abs() < 0.001);
+    }
+
+    #[test]
+    fn display_temperature_unit() {
+        assert_eq!(TemperatureUnit::Celcius.to_string(), "°C");
+        assert_eq!(TemperatureUnit::Fahrenheit.to_string(), "°F");
+    }
+
+    #[test]
+    fn readings_before_authentication_discarded() {
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let state = Arc::new(Mutex::new(DeviceState::default()));
+        let filtered = authenticated_only(receiver, state.clone());
+        futures::pin_mut!(filtered);
+        let [early, late]: [RealTimeData; 2] = readings(&[None, Some(50.0)]).try_into().unwrap();
+
+        block_on(async {
+            sender.unbounded_send(early).unwrap();
+            assert!(futures::poll!(filtered.next()).is_pending());
+            state.lock().unwrap().authenticated = true;
+            sender.unbounded_send(late.clone()).unwrap();
+            drop(sender);
+            assert_eq!(filtered.collect::<Vec<_>>().await, vec![late]);
+        });
+
+        // Early readings don't affect any other state.
+        let state = state.lock().unwrap();
+        assert_eq!(state.real_time, RealTimeState::Disabled);
+        assert!(state.targets.is_empty());
+    }
+
+    #[test]
+    fn safety_ceiling_independent_of_target() {
+        let state = Arc::new(Mutex::new(DeviceState::default()));
+        {
+            let mut state = state.lock().unwrap();
+            state.record_target(0, 40.0..60.0);
+            state.safety_ceilings.insert(0, 300.0);
+            state.safety_ceilings.insert(1, 250.0);
+        }
+        let events = [
+            [Some(50.0), Some(260.0)],
+            [Some(310.0), Some(270.0)],
+            [Some(290.0), Some(240.0)],
+        ]
+        .iter()
+        .map(|temperatures| {
+            Either::Right(RealTimeData {
+                probe_temperatures: temperatures.to_vec(),
+            })
+        });
+        let alarms: Vec<_> = block_on(alarm_events(stream::iter(events), state).collect());
+        assert_eq!(
+            alarms,
+            vec![
+                // Probe 1 has no target, but its ceiling is still checked.
+                AlarmEvent::SafetyCeilingExceeded {
+                    probe: 1,
+                    temperature: 260.0,
+                    ceiling: 250.0,
+                },
+                AlarmEvent::SafetyCeilingExceeded {
+                    probe: 0,
+                    temperature: 310.0,
+                    ceiling: 300.0,
+                },
+                AlarmEvent::SoftwareThresholdCrossed {
+                    probe: 0,
+                    temperature: 310.0,
+                    range: 40.0..60.0,
+                },
+                // Coming back below the ceiling doesn't clear the target alarm.
+                AlarmEvent::SafetyCeilingCleared {
+                    probe: 0,
+                    temperature: 290.0,
+                    ceiling: 300.0,
+                },
+                AlarmEvent::SafetyCeilingCleared {
+                    probe: 1,
+                    temperature: 240.0,
+                    ceiling: 250.0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn alarm_events_mapping() {
+        let state = Arc::new(Mutex::new(DeviceState::default()));
+        state.lock().unwrap().record_target(1, 40.0..60.0);
+        let mut events: Vec<_> = readings(&[Some(50.0), Some(60.0), Some(65.0), None, Some(30.0)])
+            .into_iter()
+            .map(Either::Right)
+            .collect();
+        events.insert(
+            3,
+            Either::Left(SettingResult::SilencePressed { probe: None }),
+        );
+        events.push(Either::Right(readings(&[Some(45.0)]).remove(0)));
+        let alarms: Vec<_> = block_on(alarm_events(stream::iter(events), state).collect());
+        assert_eq!(
+            alarms,
+            vec![
+                AlarmEvent::SoftwareThresholdCrossed {
+                    probe: 1,
+                    temperature: 60.0,
+                    range: 40.0..60.0,
+                },
+                AlarmEvent::DeviceAlarmSilenced { probe: None },
+                // Disconnecting resets the threshold, so crossing it again is reported.
+                AlarmEvent::SoftwareThresholdCrossed {
+                    probe: 1,
+                    temperature: 30.0,
+                    range: 40.0..60.0,
+                },
+                AlarmEvent::SoftwareThresholdCleared {
+                    probe: 1,
+                    temperature: 45.0,
+                    range: 40.0..60.0,
+                },
+            ]
+        );
+    }
+
+    fn readings(temperatures: &[Option<f32>]) -> Vec<RealTimeData> {
+        temperatures
+            .iter()
+            .map(|&temperature| RealTimeData {
+                probe_temperatures: vec![Some(20.0), temperature],
             })
+            .collect()
+    }
+
+    fn device_id(path: &str) -> DeviceId {
+        serde_json::from_value(serde_json::json!({ "object_path": path })).unwrap()
+    }
+
+    #[test]
+    fn disconnections_of_device() {
+        let device = device_id("/org/bluez/hci0/dev_11_11_11_11_11_11");
+        let other_device = device_id("/org/bluez/hci0/dev_22_22_22_22_22_22");
+        let connected = |id: &DeviceId, connected| BluetoothEvent::Device {
+            id: id.clone(),
+            event: DeviceEvent::Connected { connected },
+        };
+        let events = stream::iter(vec![
+            connected(&device, true),
+            BluetoothEvent::Device {
+                id: device.clone(),
+                event: DeviceEvent::Rssi { rssi: -50 },
+            },
+            connected(&other_device, false),
+            connected(&device, false),
+            connected(&device, true),
+            connected(&device, false),
+        ]);
+        let disconnections: Vec<()> = block_on(disconnections(events, device).collect());
+        assert_eq!(disconnections.len(), 2);
+    }
+
+    #[test]
+    fn raw_frame_messages() {
+        assert_eq!(
+            raw_frame_message("real-time data", REAL_TIME_DATA_UUID, &[0xAB, 0xCD]),
+            "Raw real-time data frame (0xfff4): AB CD"
+        );
+        assert_eq!(
+            raw_frame_message("setting result", SETTING_RESULT_UUID, &[]),
+            "Raw setting result frame (0xfff1): "
+        );
+    }
+
+    #[test]
+    fn raw_frames_logged_when_enabled() {
+        let characteristic: CharacteristicId = serde_json::from_value(serde_json::json!({
+            "object_path": "/org/bluez/hci0/dev_11_11_11_11_11_11/service0010/char0011"
         }))
+        .unwrap();
+        let value = |value: Vec<u8>| BluetoothEvent::Characteristic {
+            id: characteristic.clone(),
+            event: CharacteristicEvent::Value { value },
+        };
+        let state = Arc::new(Mutex::new(DeviceState::default()));
+        let messages = Mutex::new(Vec::new());
+        let frames = characteristic_values_logging_to(
+            stream::iter(vec![value(vec![0x01]), value(vec![0xAB, 0xCD])]),
+            characteristic.clone(),
+            "real-time data",
+            REAL_TIME_DATA_UUID,
+            Arc::downgrade(&state),
+            |message| messages.lock().unwrap().push(message),
+        );
+        futures::pin_mut!(frames);
+
+        block_on(async {
+            assert_eq!(frames.next().await, Some(vec![0x01]));
+            assert!(messages.lock().unwrap().is_empty());
+            state.lock().unwrap().log_raw_frames = true;
+            assert_eq!(frames.next().await, Some(vec![0xAB, 0xCD]));
+        });
+        assert_eq!(
+            *messages.lock().unwrap(),
+            vec!["Raw real-time data frame (0xfff4): AB CD"]
+        );
+    }
+
+    #[test]
+    fn parse_history_record() {
+        assert_eq!(
+            HistoryData::try_parse(&[0x01, 0x02, 0xF6, 0xFF], ByteOrder::LittleEndian),
+            Some(HistoryData {
+                probe_temperatures: vec![Some(51.3), None],
+            })
+        );
+        assert_eq!(HistoryData::try_parse(&[], ByteOrder::LittleEndian), None);
+        assert_eq!(
+            HistoryData::try_parse(&[0x01], ByteOrder::LittleEndian),
+            None
+        );
+    }
+
+    #[test]
+    fn history_records_chunked() {
+        let frames = stream::iter(vec![
+            vec![0x01, 0x02, 0xF6],
+            vec![0xFF, 0xE8, 0x03, 0x00],
+            vec![0x00],
+            vec![],
+            // Anything after the terminator is ignored.
+            vec![0x01, 0x02, 0x01, 0x02],
+        ]);
+        let records: Vec<_> =
+            block_on(history_records(frames, Some(4), ByteOrder::LittleEndian).collect());
+        assert_eq!(
+            records,
+            vec![
+                HistoryData {
+                    probe_temperatures: vec![Some(51.3), None],
+                },
+                HistoryData {
+                    probe_temperatures: vec![Some(100.0), Some(0.0)],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn history_records_unknown_length() {
+        let frames = stream::iter(vec![vec![0x01, 0x02], vec![0xE8, 0x03, 0xF6, 0xFF], vec![]]);
+        let records: Vec<_> =
+            block_on(history_records(frames, None, ByteOrder::LittleEndian).collect());
+        assert_eq!(
+            records,
+            vec![
+                HistoryData {
+                    probe_temperatures: vec![Some(51.3)],
+                },
+                HistoryData {
+                    probe_temperatures: vec![Some(100.0), None],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn history_records_empty() {
+        let frames = stream::iter(vec![vec![]]);
+        let records: Vec<_> =
+            block_on(history_records(frames, Some(4), ByteOrder::LittleEndian).collect());
+        assert_eq!(records, vec![]);
     }
-}
 
-/// The temperature unit which the thermometer uses for its display.
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
-pub enum TemperatureUnit {
-    /// ºC
-    Celcius,
-    /// ºF
-    Fahrenheit,
-}
+    #[test]
+    fn reauth_after_reconnect() {
+        let device = device_id("/org/bluez/hci0/dev_11_11_11_11_11_11");
+        let other_device = device_id("/org/bluez/hci0/dev_22_22_22_22_22_22");
+        let connected = |id: &DeviceId, connected| BluetoothEvent::Device {
+            id: id.clone(),
+            event: DeviceEvent::Connected { connected },
+        };
+        let state = Arc::new(Mutex::new(DeviceState {
+            authenticated: true,
+            ..Default::default()
+        }));
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let tracker = track_connection(receiver, device.clone(), Arc::downgrade(&state));
+        futures::pin_mut!(tracker);
+        let needs_reauth = || !state.lock().unwrap().authenticated;
+        block_on(async {
+            sender
+                .unbounded_send(connected(&other_device, false))
+                .unwrap();
+            assert!(futures::poll!(tracker.as_mut()).is_pending());
+            assert!(!needs_reauth());
 
-/// A data point from a BBQ device, giving the temperature of all connected probes.
-#[derive(Clone, Debug, PartialEq)]
-pub struct RealTimeData {
-    /// The current temperature of each probe in degrees Celcius, or None if the probe is
-    /// disconnected.
-    pub probe_temperatures: Vec<Option<f32>>,
-}
+            sender.unbounded_send(connected(&device, false)).unwrap();
+            assert!(futures::poll!(tracker.as_mut()).is_pending());
+            assert!(needs_reauth());
 
-impl RealTimeData {
-    fn try_parse(value: &[u8]) -> Option<RealTimeData> {
-        if value.len() % 2 != 0 {
-            return None;
-        }
-        Some(RealTimeData {
-            probe_temperatures: value
-                .chunks_exact(2)
-                .map(|bytes| {
-                    let temperature = decode_temperature(bytes.try_into().unwrap());
-                    if temperature == ABSENT_PROBE_VALUE {
-                        None
-                    } else {
-                        Some(temperature)
-                    }
+            // Authenticating after reconnecting clears the flag, until the next connect event.
+            sender.unbounded_send(connected(&device, true)).unwrap();
+            assert!(futures::poll!(tracker.as_mut()).is_pending());
+            state.lock().unwrap().authenticated = true;
+            assert!(!needs_reauth());
+            sender.unbounded_send(connected(&device, true)).unwrap();
+            assert!(futures::poll!(tracker.as_mut()).is_pending());
+            assert!(needs_reauth());
+
+            drop(sender);
+            tracker.await;
+        });
+    }
+
+    #[test]
+    fn stop_tracking_connection_after_drop() {
+        let device = device_id("/org/bluez/hci0/dev_11_11_11_11_11_11");
+        let state = Arc::new(Mutex::new(DeviceState::default()));
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let tracker = track_connection(receiver, device.clone(), Arc::downgrade(&state));
+        futures::pin_mut!(tracker);
+        drop(state);
+        block_on(async {
+            assert!(futures::poll!(tracker.as_mut()).is_pending());
+            sender
+                .unbounded_send(BluetoothEvent::Device {
+                    id: device,
+                    event: DeviceEvent::Connected { connected: false },
                 })
-                .collect(),
-        })
+                .unwrap();
+            // The sender is still open, so the tracker only finishes because the state is gone.
+            assert!(futures::poll!(tracker.as_mut()).is_ready());
+        });
     }
-}
 
-/// A response to some command sent to the device, or a notification.
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub enum SettingResult {
-    /// A confirmation that the given command has been received.
-    AcknowledgeCommand { command_id: u8, success: bool },
-    /// The current battery level of the device.
-    BatteryLevel {
-        current_voltage: u16,
-        max_voltage: u16,
-    },
-    /// A notification that the button on the device has been pressed to stop the target temperature
-    /// alarm sounding.
-    SilencePressed,
-}
+    #[test]
+    fn wait_for_temperature_reached() {
+        let readings = stream::iter(readings(&[Some(50.0), Some(60.0), Some(70.0)]));
+        assert!(matches!(
+            block_on(wait_for_temperature(readings, 1, 60.0)),
+            Ok(())
+        ));
+    }
 
-impl SettingResult {
-    fn try_parse(value: &[u8]) -> Option<SettingResult> {
-        if value.len() != 6 {
-            return None;
+    #[test]
+    fn wait_for_temperature_probe_disconnected() {
+        let readings = stream::iter(readings(&[Some(50.0), None, Some(70.0)]));
+        assert!(matches!(
+            block_on(wait_for_temperature(readings, 1, 60.0)),
+            Err(Error::ProbeDisconnected(1))
+        ));
+    }
+
+    #[test]
+    fn wait_for_temperature_probe_absent() {
+        let readings = stream::iter(readings(&[Some(50.0)]));
+        assert!(matches!(
+            block_on(wait_for_temperature(readings, 2, 60.0)),
+            Err(Error::ProbeDisconnected(2))
+        ));
+    }
+
+    #[test]
+    fn wait_for_temperature_stream_ended() {
+        let readings = stream::iter(readings(&[Some(50.0), Some(55.0)]));
+        assert!(matches!(
+            block_on(wait_for_temperature(readings, 1, 60.0)),
+            Err(Error::StreamEnded)
+        ));
+    }
+
+    fn device_info(path: &str, name: Option<&str>, mac_address: &str) -> DeviceInfo {
+        DeviceInfo {
+            id: device_id(path),
+            mac_address: mac_address.parse().unwrap(),
+            address_type: AddressType::Public,
+            name: name.map(ToOwned::to_owned),
+            appearance: None,
+            services: vec![],
+            paired: false,
+            connected: false,
+            rssi: Some(-60),
+            tx_power: None,
+            manufacturer_data: HashMap::new(),
+            service_data: HashMap::new(),
+            services_resolved: false,
+            alias: None,
+            class: None,
+            bonded: false,
+            icon: None,
+            trusted: false,
+            blocked: false,
+            legacy_pairing: false,
+            modalias: None,
+            wake_allowed: false,
         }
-        match value[0] {
-            ACKNOWLEDGE_COMMAND => {
-                let success = value[2..] == [0, 0, 0, 0];
-                if !success && value[2..] != [5, 0, 0, 0] {
-                    info!("Unrecognised acknowledge: {:?}", value);
-                }
-                Some(SettingResult::AcknowledgeCommand {
-                    command_id: value[1],
-                    success,
-                })
-            }
-            BATTERY_LEVEL_PROPERTY_ID => Some(SettingResult::BatteryLevel {
-                current_voltage: u16::from_le_bytes(value[1..=2].try_into().unwrap()),
-                max_voltage: u16::from_le_bytes(value[3..=4].try_into().unwrap()),
-            }),
-            SILENCE_COMMAND => {
-                assert!(value[1..] == [SILENCE_ARGUMENT, 0, 0, 0, 0]);
-                Some(SettingResult::SilencePressed)
-            }
-            _ => {
-                info!("Unrecognised setting result: {:?}", value);
-                None
-            }
+    }
+
+    fn crowded_devices() -> Vec<DeviceInfo> {
+        vec![
+            device_info(
+                "/org/bluez/hci0/dev_11_11_11_11_11_11",
+                Some("iBBQ"),
+                "11:11:11:11:11:11",
+            ),
+            device_info(
+                "/org/bluez/hci0/dev_22_22_22_22_22_22",
+                Some("Phone"),
+                "22:22:22:22:22:22",
+            ),
+            device_info(
+                "/org/bluez/hci0/dev_33_33_33_33_33_33",
+                Some("iBBQ"),
+                "33:33:33:33:33:33",
+            ),
+            device_info(
+                "/org/bluez/hci1/dev_11_11_11_11_11_11",
+                Some("iBBQ"),
+                "11:11:11:11:11:11",
+            ),
+            device_info(
+                "/org/bluez/hci0/dev_44_44_44_44_44_44",
+                Some("BBQ"),
+                "44:44:44:44:44:44",
+            ),
+        ]
+    }
+
+    fn addresses(devices: &[DeviceInfo]) -> Vec<String> {
+        devices
+            .iter()
+            .map(|device| device.mac_address.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn filter_devices_default() {
+        let devices = filter_devices(crowded_devices(), &DiscoveryOptions::default());
+        assert_eq!(
+            addresses(&devices),
+            vec![
+                "11:11:11:11:11:11",
+                "33:33:33:33:33:33",
+                "11:11:11:11:11:11",
+                "44:44:44:44:44:44"
+            ]
+        );
+    }
+
+    #[test]
+    fn filter_devices_dedupe() {
+        let options = DiscoveryOptions {
+            dedupe_by_address: true,
+            ..Default::default()
+        };
+        let devices = filter_devices(crowded_devices(), &options);
+        assert_eq!(
+            addresses(&devices),
+            vec![
+                "11:11:11:11:11:11",
+                "33:33:33:33:33:33",
+                "44:44:44:44:44:44"
+            ]
+        );
+    }
+
+    #[test]
+    fn filter_devices_max_results() {
+        let options = DiscoveryOptions {
+            dedupe_by_address: true,
+            max_results: Some(2),
+            ..Default::default()
+        };
+        let devices = filter_devices(crowded_devices(), &options);
+        assert_eq!(
+            addresses(&devices),
+            vec!["11:11:11:11:11:11", "33:33:33:33:33:33"]
+        );
+    }
+
+    #[test]
+    fn filter_devices_service_uuid() {
+        let mut unnamed = device_info(
+            "/org/bluez/hci0/dev_55_55_55_55_55_55",
+            None,
+            "55:55:55:55:55:55",
+        );
+        unnamed.services = vec![BBQ_SERVICE_UUID];
+        let mut other = unnamed.clone();
+        other.name = Some("Speaker".to_owned());
+        let devices = vec![unnamed, other];
+        assert!(filter_devices(devices.clone(), &DiscoveryOptions::default()).is_empty());
+        let options = DiscoveryOptions {
+            match_service_uuid: true,
+            ..Default::default()
+        };
+        let devices = filter_devices(devices, &options);
+        assert_eq!(addresses(&devices), vec!["55:55:55:55:55:55"]);
+        assert_eq!(devices[0].name, None);
+    }
+
+    #[test]
+    fn compatible_names() {
+        for name in ["BBQ", "iBBQ", "BBQ_D", "ibbq-1234", "bbq"] {
+            assert!(is_compatible_name(name), "{}", name);
+        }
+        for name in ["Grill", "MyBBQ", "", "BB"] {
+            assert!(!is_compatible_name(name), "{}", name);
         }
     }
-}
 
-fn encode_temperature(temperature: f32) -> Result<[u8; 2], Error> {
-    if temperature < TEMPERATURE_MIN || temperature > TEMPERATURE_MAX {
-        return Err(Error::TemperatureEncodingError(temperature));
+    #[test]
+    fn lookup_by_name_prefix() {
+        let table = [("BBQ", 1), ("iBBQ", 2)];
+        assert_eq!(lookup_by_name(&table, Some("BBQ")), Some(&1));
+        assert_eq!(lookup_by_name(&table, Some("bbq_d")), Some(&1));
+        assert_eq!(lookup_by_name(&table, Some("iBBQ-2")), Some(&2));
+        assert_eq!(lookup_by_name(&table, Some("IBBQ")), Some(&2));
+        assert_eq!(lookup_by_name(&table, Some("Grill")), None);
+        assert_eq!(lookup_by_name(&table, None), None);
     }
-    let temperature_fixed = (temperature * 10.0) as i16;
-    Ok(temperature_fixed.to_le_bytes())
-}
 
-fn decode_temperature(bytes: [u8; 2]) -> f32 {
-    i16::from_le_bytes(bytes) as f32 / 10.0
-}
+    #[test]
+    fn filter_devices_extra_names() {
+        let mut devices = crowded_devices();
+        devices.push(device_info(
+            "/org/bluez/hci0/dev_55_55_55_55_55_55",
+            Some("GRILL-2000"),
+            "55:55:55:55:55:55",
+        ));
+        assert!(!addresses(&filter_devices(
+            devices.clone(),
+            &DiscoveryOptions::default()
+        ))
+        .contains(&"55:55:55:55:55:55".to_owned()));
+        let options = DiscoveryOptions {
+            extra_names: vec!["grill".to_owned()],
+            dedupe_by_address: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            addresses(&filter_devices(devices, &options)),
+            vec![
+                "11:11:11:11:11:11",
+                "33:33:33:33:33:33",
+                "44:44:44:44:44:44",
+                "55:55:55:55:55:55"
+            ]
+        );
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn device_names_compatible() {
+        for name in DEVICE_NAMES {
+            assert!(BBQDevice::is_compatible(&device_info(
+                "/org/bluez/hci0/dev_11_11_11_11_11_11",
+                Some(name),
+                "11:11:11:11:11:11"
+            )));
+        }
+        assert!(!BBQDevice::is_compatible(&device_info(
+            "/org/bluez/hci0/dev_11_11_11_11_11_11",
+            Some("Speaker"),
+            "11:11:11:11:11:11"
+        )));
+    }
 
     #[test]
-    fn parse_real_time_invalid() {
-        assert_eq!(RealTimeData::try_parse(&[0]), None);
+    fn reconstructed_times() {
+        let end_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        assert_eq!(
+            with_reconstructed_times(vec!['a', 'b', 'c'], Duration::from_secs(60), end_time),
+            vec![
+                (SystemTime::UNIX_EPOCH + Duration::from_secs(880), 'a'),
+                (SystemTime::UNIX_EPOCH + Duration::from_secs(940), 'b'),
+                (end_time, 'c'),
+            ]
+        );
+        assert_eq!(
+            with_reconstructed_times(Vec::<()>::new(), Duration::from_secs(60), end_time),
+            vec![]
+        );
     }
 
     #[test]
-    fn parse_real_time_no_probes() {
+    fn reconstructed_times_too_old() {
+        let end_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
         assert_eq!(
-            RealTimeData::try_parse(&[0xF6, 0xFF, 0xF6, 0xFF]),
-            Some(RealTimeData {
-                probe_temperatures: vec![None, None]
+            with_reconstructed_times(vec!['a', 'b', 'c'], Duration::MAX, end_time),
+            vec![(end_time, 'c')]
+        );
+    }
+
+    #[test]
+    fn influx_line() {
+        let reading = RealTimeData {
+            probe_temperatures: vec![Some(51.3), None, Some(22.0)],
+        };
+        let timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(
+            reading.to_influx_line("bbq", timestamp),
+            "bbq probe0=51.3,probe2=22 1700000000000000000"
+        );
+        assert_eq!(
+            reading.to_influx_line("my bbq,1", timestamp),
+            "my\\ bbq\\,1 probe0=51.3,probe2=22 1700000000000000000"
+        );
+    }
+
+    #[test]
+    fn influx_line_disconnected() {
+        let reading = RealTimeData {
+            probe_temperatures: vec![None, None],
+        };
+        assert_eq!(reading.to_influx_line("bbq", SystemTime::UNIX_EPOCH), "");
+    }
+
+    #[test]
+    fn dedup_history_records() {
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(
+            dedup_history(vec![
+                (at(60), 'b'),
+                (at(0), 'a'),
+                (at(60), 'x'),
+                (at(120), 'c'),
+                (at(0), 'y'),
+            ]),
+            vec![(at(0), 'a'), (at(60), 'b'), (at(120), 'c')]
+        );
+    }
+
+    #[test]
+    fn history_csv() {
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let records = vec![
+            (
+                at(1700000000),
+                HistoryData {
+                    probe_temperatures: vec![Some(51.3), None],
+                },
+            ),
+            (
+                at(1700000060),
+                HistoryData {
+                    probe_temperatures: vec![Some(52.0), Some(22.5), None],
+                },
+            ),
+        ];
+        let mut csv = Vec::new();
+        write_history_csv(&mut csv, &records).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "time,probe0,probe1,probe2\n1700000000,51.3,,\n1700000060,52,22.5,\n"
+        );
+
+        let mut csv = Vec::new();
+        write_history_csv(&mut csv, &[]).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap(), "time\n");
+    }
+
+    #[test]
+    fn credential_for_known_names() {
+        assert_eq!(credential_for_name(Some("iBBQ")), DEFAULT_CREDENTIAL);
+        assert_eq!(credential_for_name(Some("BBQ")), DEFAULT_CREDENTIAL);
+        assert_eq!(credential_for_name(Some("bbq_d")), DEFAULT_CREDENTIAL);
+        assert_eq!(credential_for_name(Some("iBBQ-2")), DEFAULT_CREDENTIAL);
+    }
+
+    #[test]
+    fn credential_for_unknown_names() {
+        assert_eq!(credential_for_name(Some("Grill")), DEFAULT_CREDENTIAL);
+        assert_eq!(credential_for_name(None), DEFAULT_CREDENTIAL);
+    }
+
+    #[test]
+    fn summary_includes_address() {
+        let device = device_info(
+            "/org/bluez/hci0/dev_11_11_11_11_11_11",
+            Some("iBBQ"),
+            "11:11:11:11:11:11",
+        );
+        assert_eq!(
+            device_summary(&device),
+            "iBBQ (11:11:11:11:11:11, RSSI -60)"
+        );
+    }
+
+    fn characteristic(path: &str, uuid: Uuid, flags: CharacteristicFlags) -> CharacteristicInfo {
+        CharacteristicInfo {
+            id: serde_json::from_value(serde_json::json!({ "object_path": path })).unwrap(),
+            uuid,
+            flags,
+            mtu: None,
+        }
+    }
+
+    #[test]
+    fn describe() {
+        let characteristics = vec![
+            characteristic(
+                "/org/bluez/hci0/dev_11_22_33_44_55_66/service0010/char0011",
+                SETTING_RESULT_UUID,
+                CharacteristicFlags::READ | CharacteristicFlags::NOTIFY,
+            ),
+            characteristic(
+                "/org/bluez/hci0/dev_11_22_33_44_55_66/service0010/char0014",
+                SETTING_DATA_UUID,
+                CharacteristicFlags::WRITE,
+            ),
+            characteristic(
+                "/org/bluez/hci0/dev_11_22_33_44_55_66/service0010/char0017",
+                uuid_from_u16(0xFFF9),
+                CharacteristicFlags::READ,
+            ),
+        ];
+        let mut notifying = HashSet::new();
+        notifying.insert(characteristics[0].id.clone());
+        assert_eq!(
+            describe_characteristics(&characteristics, &notifying),
+            "BBQ service 0xfff0:\n  \
+             0xfff1 (setting result): read, notify, notifying\n  \
+             0xfff5 (setting data): write\n  \
+             0xfff9 (unknown): read\n"
+        );
+    }
+
+    #[test]
+    fn collect_handshake_info_complete() {
+        let setting_results = stream::iter(vec![
+            SettingResult::AcknowledgeCommand {
+                command_id: REAL_TIME_DATA_COMMAND,
+                success: true,
+                status: AckStatus::Success,
+            },
+            SettingResult::BatteryLevel {
+                current_voltage: 5979,
+                max_voltage: 6550,
+            },
+        ]);
+        let readings = stream::iter(vec![RealTimeData {
+            probe_temperatures: vec![Some(20.0), None, None, None],
+        }]);
+        let mut info = HandshakeInfo::default();
+        block_on(collect_handshake_info(&mut info, setting_results, readings));
+        assert_eq!(
+            info,
+            HandshakeInfo {
+                current_voltage: Some(5979),
+                max_voltage: Some(6550),
+                probe_count: Some(4),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn collect_handshake_info_partial() {
+        let setting_results = stream::pending();
+        let readings = stream::iter(vec![RealTimeData {
+            probe_temperatures: vec![None, None],
+        }])
+        .chain(stream::pending());
+        let mut info = HandshakeInfo::default();
+        let result = tokio::time::timeout(
+            Duration::from_millis(10),
+            collect_handshake_info(&mut info, setting_results, readings),
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(
+            info,
+            HandshakeInfo {
+                current_voltage: None,
+                max_voltage: None,
+                probe_count: Some(2),
+            }
+        );
+    }
+
+    #[test]
+    fn check_targets_nonexistent_probe() {
+        let mut targets = HashMap::new();
+        targets.insert(1, 100.0..120.0);
+        assert!(check_targets(&targets, 2).is_ok());
+        targets.insert(5, 100.0..120.0);
+        targets.insert(2, 100.0..120.0);
+        assert!(matches!(
+            check_targets(&targets, 2),
+            Err(Error::NoSuchProbe {
+                probe: 2,
+                probe_count: 2
             })
+        ));
+    }
+
+    #[test]
+    fn setting_result_ids() {
+        assert_eq!(
+            SettingResultId::try_from(0xFF),
+            Ok(SettingResultId::Acknowledge)
+        );
+        assert_eq!(
+            SettingResultId::try_from(0x24),
+            Ok(SettingResultId::BatteryLevel)
         );
+        assert_eq!(
+            SettingResultId::try_from(0x04),
+            Ok(SettingResultId::SilencePressed)
+        );
+        assert_eq!(SettingResultId::try_from(0x42), Err(0x42));
     }
 
     #[test]
-    fn parse_real_time() {
+    fn parse_setting_result_unrecognised() {
         assert_eq!(
-            RealTimeData::try_parse(&[1, 2, 3, 4]),
-            Some(RealTimeData {
-                probe_temperatures: vec![Some(51.3), Some(102.7)]
+            SettingResult::try_parse(&[0x42, 0x01, 0x02, 0x03, 0x04, 0x05]),
+            Some(SettingResult::Unknown {
+                raw: vec![0x42, 0x01, 0x02, 0x03, 0x04, 0x05]
             })
         );
     }
@@ -408,7 +4072,8 @@ mod tests {
             SettingResult::try_parse(&[0xFF, 0x02, 0x00, 0x00, 0x00, 0x00]),
             Some(SettingResult::AcknowledgeCommand {
                 command_id: 0x02,
-                success: true
+                success: true,
+                status: AckStatus::Success
             })
         );
     }
@@ -419,7 +4084,57 @@ mod tests {
             SettingResult::try_parse(&[0xFF, 0x01, 0x05, 0x00, 0x00, 0x00]),
             Some(SettingResult::AcknowledgeCommand {
                 command_id: SET_TARGET_TEMP_COMMAND,
-                success: false
+                success: false,
+                status: AckStatus::InvalidProbe
+            })
+        );
+    }
+
+    #[test]
+    fn parse_setting_result_acknowledge_unknown_status() {
+        assert_eq!(
+            SettingResult::try_parse(&[0xFF, 0x02, 0x07, 0x01, 0x00, 0x00]),
+            Some(SettingResult::AcknowledgeCommand {
+                command_id: SET_UNIT_COMMAND,
+                success: false,
+                status: AckStatus::Unknown([0x07, 0x01, 0x00, 0x00]),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_setting_result_acknowledge_truncated() {
+        assert_eq!(SettingResult::try_parse(&[0xFF]), None);
+        assert_eq!(SettingResult::try_parse(&[0xFF, 0x02]), None);
+        assert_eq!(
+            SettingResult::try_parse(&[0xFF, 0x02, 0x00, 0x00, 0x00]),
+            None
+        );
+        assert_eq!(
+            SettingResult::try_parse(&[0xFF, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00]),
+            None
+        );
+        // A truncated frame isn't reported as `Unknown` either, even if its type isn't recognised.
+        assert_eq!(SettingResult::try_parse(&[0x42, 0x01]), None);
+    }
+
+    #[test]
+    fn parse_setting_result_acknowledge_garbage() {
+        assert_eq!(
+            SettingResult::try_parse(&[0xFF, 0x01, 0x12, 0x34, 0x56, 0x78]),
+            Some(SettingResult::AcknowledgeCommand {
+                command_id: SET_TARGET_TEMP_COMMAND,
+                success: false,
+                status: AckStatus::Unknown([0x12, 0x34, 0x56, 0x78]),
+            })
+        );
+        // An otherwise successful status with trailing garbage isn't taken as success.
+        assert_eq!(
+            SettingResult::try_parse(&[0xFF, 0x02, 0x00, 0x00, 0x00, 0x01]),
+            Some(SettingResult::AcknowledgeCommand {
+                command_id: SET_UNIT_COMMAND,
+                success: false,
+                status: AckStatus::Unknown([0x00, 0x00, 0x00, 0x01]),
             })
         );
     }
@@ -435,11 +4150,62 @@ mod tests {
         );
     }
 
+    #[test]
+    fn battery_percentage() {
+        let percentage = SettingResult::BatteryLevel {
+            current_voltage: 5979,
+            max_voltage: 6550,
+        }
+        .battery_percentage()
+        .unwrap();
+        assert!((percentage - 91.28).abs() < 0.01, "{}", percentage);
+        assert_eq!(
+            SettingResult::BatteryLevel {
+                current_voltage: 7000,
+                max_voltage: 6550,
+            }
+            .battery_percentage(),
+            Some(100.0)
+        );
+        assert_eq!(
+            SettingResult::BatteryLevel {
+                current_voltage: 5979,
+                max_voltage: 0,
+            }
+            .battery_percentage(),
+            None
+        );
+        assert_eq!(
+            SettingResult::SilencePressed { probe: None }.battery_percentage(),
+            None
+        );
+    }
+
     #[test]
     fn parse_setting_result_silence_pressed() {
         assert_eq!(
             SettingResult::try_parse(&[0x04, 0xFF, 0x00, 0x00, 0x00, 0x00]),
-            Some(SettingResult::SilencePressed)
+            Some(SettingResult::SilencePressed { probe: None })
+        );
+    }
+
+    #[test]
+    fn parse_setting_result_silence_pressed_probe() {
+        assert_eq!(
+            SettingResult::try_parse(&[0x04, 0x02, 0x00, 0x00, 0x00, 0x00]),
+            Some(SettingResult::SilencePressed { probe: Some(2) })
+        );
+    }
+
+    #[test]
+    fn parse_setting_result_silence_garbage() {
+        assert_eq!(
+            SettingResult::try_parse(&[0x04, 0xFF, 0x12, 0x34, 0x56, 0x78]),
+            None
+        );
+        assert_eq!(
+            SettingResult::try_parse(&[0x04, 0x00, 0x00, 0x00, 0x00, 0x01]),
+            None
         );
     }
 }
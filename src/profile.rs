@@ -0,0 +1,137 @@
+//! Support for devices which encode their commands slightly differently.
+
+use crate::{
+    lookup_by_name, REAL_TIME_DATA_COMMAND, REQUEST_PROPERTY_COMMAND, SET_TARGET_TEMP_COMMAND,
+    SET_UNIT_COMMAND, SILENCE_COMMAND,
+};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// The byte order in which a device encodes temperatures.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub enum ByteOrder {
+    /// Least significant byte first.
+    #[default]
+    LittleEndian,
+    /// Most significant byte first.
+    BigEndian,
+}
+
+/// Details of how a particular model of device encodes the commands written to its 'setting data'
+/// characteristic.
+///
+/// All devices known so far use `DeviceProfile::STANDARD`. If you have a device which needs
+/// something different, construct a profile for it and pass it to `BBQDevice::set_profile`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct DeviceProfile {
+    /// The length of each command frame. Commands are padded with zeroes to this length.
+    pub frame_length: usize,
+    /// The byte order of temperatures in commands and real-time data. A device which reports
+    /// temperatures which are wildly wrong may need `ByteOrder::BigEndian`.
+    pub byte_order: ByteOrder,
+    /// Whether the device only accepts target temperatures in whole degrees Celsius, rather than
+    /// tenths of a degree. Targets are rounded to the nearest degree before being sent to such
+    /// devices. No devices known so far need this.
+    pub whole_degree_targets: bool,
+    /// The command ID to set the target temperature range for a probe.
+    pub set_target_temp_command: u8,
+    /// The command ID to set the temperature unit for the display.
+    pub set_unit_command: u8,
+    /// The command ID to silence the alarm.
+    pub silence_command: u8,
+    /// The command ID to request that the device report a property such as the battery level.
+    pub request_property_command: u8,
+    /// The command ID to enable or disable real-time data.
+    pub real_time_data_command: u8,
+    /// The command ID to request that the device send its recorded history, if known. No devices
+    /// known so far have one, so this is `None` in `DeviceProfile::STANDARD`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub request_history_command: Option<u8>,
+}
+
+/// Profiles known to be needed by particular devices, keyed by a prefix of the name which the
+/// device advertises. Devices not matching any of these use `DeviceProfile::STANDARD`.
+pub const KNOWN_PROFILES: &[(&str, DeviceProfile)] = &[
+    ("BBQ", DeviceProfile::STANDARD),
+    ("iBBQ", DeviceProfile::STANDARD),
+];
+
+impl DeviceProfile {
+    /// The command encoding described by the protocol spec, used by all devices known so far.
+    pub const STANDARD: DeviceProfile = DeviceProfile {
+        frame_length: 6,
+        byte_order: ByteOrder::LittleEndian,
+        whole_degree_targets: false,
+        set_target_temp_command: SET_TARGET_TEMP_COMMAND,
+        set_unit_command: SET_UNIT_COMMAND,
+        silence_command: SILENCE_COMMAND,
+        request_property_command: REQUEST_PROPERTY_COMMAND,
+        real_time_data_command: REAL_TIME_DATA_COMMAND,
+        request_history_command: None,
+    };
+
+    /// Return the profile to use for a device with the given name. Names are matched against
+    /// `KNOWN_PROFILES` by prefix ignoring case, as by `is_compatible_name`, so e.g. `BBQ_D` gets the
+    /// profile for `BBQ`.
+    pub fn for_name(name: Option<&str>) -> DeviceProfile {
+        lookup_by_name(KNOWN_PROFILES, name).map_or(DeviceProfile::STANDARD, Clone::clone)
+    }
+
+    /// Pad the given command with zeroes to the frame length.
+    pub(crate) fn frame(&self, command: &[u8]) -> Vec<u8> {
+        let mut frame = command.to_vec();
+        if frame.len() < self.frame_length {
+            frame.resize(self.frame_length, 0);
+        }
+        frame
+    }
+
+    /// Round the given target temperature as required by the device.
+    pub(crate) fn round_target(&self, temperature: f32) -> f32 {
+        if self.whole_degree_targets {
+            temperature.round()
+        } else {
+            temperature
+        }
+    }
+
+    /// A hypothetical clone which uses 7-byte big-endian frames with different command IDs.
+    #[cfg(test)]
+    pub(crate) const TEST_CLONE: DeviceProfile = DeviceProfile {
+        frame_length: 7,
+        byte_order: ByteOrder::BigEndian,
+        whole_degree_targets: false,
+        set_target_temp_command: 0x11,
+        set_unit_command: 0x12,
+        silence_command: 0x14,
+        request_property_command: 0x18,
+        real_time_data_command: 0x1B,
+        request_history_command: Some(0x1C),
+    };
+}
+
+impl Default for DeviceProfile {
+    fn default() -> Self {
+        DeviceProfile::STANDARD
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_for_name() {
+        assert_eq!(
+            DeviceProfile::for_name(Some("iBBQ")),
+            DeviceProfile::STANDARD
+        );
+        assert_eq!(
+            DeviceProfile::for_name(Some("ibbq-2")),
+            DeviceProfile::STANDARD
+        );
+        assert_eq!(DeviceProfile::for_name(None), DeviceProfile::STANDARD);
+    }
+}
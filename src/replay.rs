@@ -0,0 +1,222 @@
+//! A fake device which replays recorded frames, for developing without hardware.
+
+use crate::{ByteOrder, RealTimeData, SettingResult};
+use futures::stream::{self, Stream, StreamExt};
+use std::time::Duration;
+use thiserror::Error;
+
+/// The characteristic on which a replayed frame was received.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Characteristic {
+    RealTime,
+    SettingResult,
+}
+
+/// A single frame in a replay fixture.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct ReplayFrame {
+    /// How long to wait after the previous frame before this one.
+    delay: Duration,
+    characteristic: Characteristic,
+    value: Vec<u8>,
+}
+
+/// An event replayed by a `ReplayDevice`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReplayEvent {
+    /// A reading, as would be returned by `BBQDevice::real_time`.
+    RealTime(RealTimeData),
+    /// A setting result, as would be returned by `BBQDevice::setting_results`.
+    SettingResult(SettingResult),
+}
+
+/// An error parsing a replay fixture.
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+#[error("Invalid replay fixture line {line}: {message}")]
+pub struct ParseFixtureError {
+    line: usize,
+    message: String,
+}
+
+/// A fake device which plays back recorded frames from a fixture, parsed in the same way as frames
+/// from a real device. This is useful for developing UIs without a thermometer.
+///
+/// A fixture has one frame per line, giving the delay in milliseconds since the previous frame, the
+/// characteristic it was received on (`real-time` or `setting-result`), and the bytes of the frame
+/// in hex. Blank lines and lines starting with `#` are ignored. For example:
+///
+/// ```text
+/// # Probe 0 is plugged in, then the alarm is silenced.
+/// 0 real-time F6 FF F6 FF
+/// 1000 real-time D2 00 F6 FF
+/// 500 setting-result 04 FF 00 00 00 00
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplayDevice {
+    frames: Vec<ReplayFrame>,
+    speed: f32,
+    byte_order: ByteOrder,
+}
+
+impl ReplayDevice {
+    /// Parse a replay fixture in the format described above.
+    pub fn from_fixture(fixture: &str) -> Result<Self, ParseFixtureError> {
+        let frames = fixture
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+            .map(|(index, line)| {
+                parse_frame(line).map_err(|message| ParseFixtureError {
+                    line: index + 1,
+                    message,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            frames,
+            speed: 1.0,
+            byte_order: ByteOrder::default(),
+        })
+    }
+
+    /// Play back the frames faster (or slower) than recorded, by the given factor.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `speed` is not positive.
+    pub fn with_speed(self, speed: f32) -> Self {
+        assert!(speed > 0.0, "speed must be positive");
+        Self { speed, ..self }
+    }
+
+    /// Decode real-time data with the given byte order, for fixtures recorded from a device whose
+    /// profile uses `ByteOrder::BigEndian`. The default is `ByteOrder::LittleEndian`.
+    pub fn with_byte_order(self, byte_order: ByteOrder) -> Self {
+        Self { byte_order, ..self }
+    }
+
+    /// Replay all the frames from the start, with their recorded delays.
+    ///
+    /// This must be called from within a Tokio runtime.
+    pub fn events(&self) -> impl Stream<Item = ReplayEvent> {
+        let speed = self.speed;
+        let byte_order = self.byte_order;
+        stream::iter(self.frames.clone())
+            .then(move |frame| async move {
+                tokio::time::sleep(frame.delay.div_f32(speed)).await;
+                frame
+            })
+            .filter_map(move |frame| async move {
+                match frame.characteristic {
+                    Characteristic::RealTime => {
+                        RealTimeData::try_parse(&frame.value, byte_order).map(ReplayEvent::RealTime)
+                    }
+                    Characteristic::SettingResult => {
+                        SettingResult::try_parse(&frame.value).map(ReplayEvent::SettingResult)
+                    }
+                }
+            })
+    }
+
+    /// Replay the real-time data from the fixture, like `BBQDevice::real_time`.
+    pub fn real_time(&self) -> impl Stream<Item = RealTimeData> {
+        self.events().filter_map(|event| async move {
+            match event {
+                ReplayEvent::RealTime(reading) => Some(reading),
+                ReplayEvent::SettingResult(_) => None,
+            }
+        })
+    }
+
+    /// Replay the setting results from the fixture, like `BBQDevice::setting_results`.
+    pub fn setting_results(&self) -> impl Stream<Item = SettingResult> {
+        self.events().filter_map(|event| async move {
+            match event {
+                ReplayEvent::SettingResult(result) => Some(result),
+                ReplayEvent::RealTime(_) => None,
+            }
+        })
+    }
+}
+
+fn parse_frame(line: &str) -> Result<ReplayFrame, String> {
+    let mut parts = line.split_whitespace();
+    let delay = parts.next().ok_or("missing delay")?;
+    let delay = Duration::from_millis(
+        delay
+            .parse()
+            .map_err(|_| format!("invalid delay {:?}", delay))?,
+    );
+    let characteristic = match parts.next() {
+        Some("real-time") => Characteristic::RealTime,
+        Some("setting-result") => Characteristic::SettingResult,
+        Some(other) => return Err(format!("unknown characteristic {:?}", other)),
+        None => return Err("missing characteristic".to_owned()),
+    };
+    let value = parts
+        .map(|byte| u8::from_str_radix(byte, 16).map_err(|_| format!("invalid byte {:?}", byte)))
+        .collect::<Result<_, _>>()?;
+    Ok(ReplayFrame {
+        delay,
+        characteristic,
+        value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Instant;
+
+    const FIXTURE: &str = "
+        # Probe 0 is plugged in, then the alarm is silenced.
+        0 real-time F6 FF F6 FF
+        1000 real-time D2 00 F6 FF
+        500 setting-result 04 FF 00 00 00 00
+    ";
+
+    #[tokio::test(start_paused = true)]
+    async fn replay_fixture() {
+        let device = ReplayDevice::from_fixture(FIXTURE).unwrap().with_speed(2.0);
+        let start = Instant::now();
+        let events: Vec<_> = device.events().collect().await;
+        assert_eq!(
+            events,
+            vec![
+                ReplayEvent::RealTime(RealTimeData {
+                    probe_temperatures: vec![None, None],
+                }),
+                ReplayEvent::RealTime(RealTimeData {
+                    probe_temperatures: vec![Some(21.0), None],
+                }),
+                ReplayEvent::SettingResult(SettingResult::SilencePressed { probe: None }),
+            ]
+        );
+        assert_eq!(start.elapsed(), Duration::from_millis(750));
+        assert_eq!(device.real_time().count().await, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replay_big_endian() {
+        let device = ReplayDevice::from_fixture("0 real-time 00 D2 FF F6")
+            .unwrap()
+            .with_byte_order(ByteOrder::BigEndian);
+        assert_eq!(
+            device.real_time().collect::<Vec<_>>().await,
+            vec![RealTimeData {
+                probe_temperatures: vec![Some(21.0), None],
+            }]
+        );
+    }
+
+    #[test]
+    fn invalid_fixture() {
+        assert_eq!(
+            ReplayDevice::from_fixture("0 real-time F6 FF\n10 history 00"),
+            Err(ParseFixtureError {
+                line: 2,
+                message: "unknown characteristic \"history\"".to_owned(),
+            })
+        );
+    }
+}
@@ -0,0 +1,112 @@
+//! Tests which replay recorded traffic from a whole session through the parsers.
+
+use crate::{AckStatus, ByteOrder, RealTimeData, SettingResult};
+
+/// A frame received from the device, tagged with the characteristic it was received on.
+#[derive(Clone, Copy, Debug)]
+enum Frame {
+    SettingResult(&'static [u8]),
+    RealTime(&'static [u8]),
+}
+
+/// The result of parsing a `Frame`.
+#[derive(Clone, Debug, PartialEq)]
+enum Parsed {
+    SettingResult(Option<SettingResult>),
+    RealTime(Option<RealTimeData>),
+}
+
+fn replay(frames: &[Frame]) -> Vec<Parsed> {
+    frames
+        .iter()
+        .map(|frame| match frame {
+            Frame::SettingResult(value) => Parsed::SettingResult(SettingResult::try_parse(value)),
+            Frame::RealTime(value) => {
+                Parsed::RealTime(RealTimeData::try_parse(value, ByteOrder::LittleEndian))
+            }
+        })
+        .collect()
+}
+
+/// A session with a 4-probe device: after authenticating, the app sets the unit and a target on
+/// probe 0, requests the battery level and enables real-time data. Probe 0 is then plugged in,
+/// followed by probe 1, which is later unplugged. Finally the alarm is silenced on the device.
+const SESSION: &[Frame] = &[
+    Frame::SettingResult(&[0xFF, 0x02, 0x00, 0x00, 0x00, 0x00]),
+    Frame::SettingResult(&[0xFF, 0x01, 0x00, 0x00, 0x00, 0x00]),
+    Frame::SettingResult(&[0x24, 0x5B, 0x17, 0x96, 0x19, 0x00]),
+    Frame::SettingResult(&[0xFF, 0x0B, 0x00, 0x00, 0x00, 0x00]),
+    Frame::RealTime(&[0xF6, 0xFF, 0xF6, 0xFF, 0xF6, 0xFF, 0xF6, 0xFF]),
+    Frame::RealTime(&[0xD2, 0x00, 0xF6, 0xFF, 0xF6, 0xFF, 0xF6, 0xFF]),
+    Frame::RealTime(&[0xE1, 0x00, 0xC6, 0x00, 0xF6, 0xFF, 0xF6, 0xFF]),
+    Frame::RealTime(&[0x5E, 0x01, 0xF6, 0xFF, 0xF6, 0xFF, 0xF6, 0xFF]),
+    Frame::SettingResult(&[0x04, 0xFF, 0x00, 0x00, 0x00, 0x00]),
+];
+
+#[test]
+fn replay_session() {
+    assert_eq!(
+        replay(SESSION),
+        vec![
+            Parsed::SettingResult(Some(SettingResult::AcknowledgeCommand {
+                command_id: 0x02,
+                success: true,
+                status: AckStatus::Success,
+            })),
+            Parsed::SettingResult(Some(SettingResult::AcknowledgeCommand {
+                command_id: 0x01,
+                success: true,
+                status: AckStatus::Success,
+            })),
+            Parsed::SettingResult(Some(SettingResult::BatteryLevel {
+                current_voltage: 5979,
+                max_voltage: 6550,
+            })),
+            Parsed::SettingResult(Some(SettingResult::AcknowledgeCommand {
+                command_id: 0x0B,
+                success: true,
+                status: AckStatus::Success,
+            })),
+            Parsed::RealTime(Some(RealTimeData {
+                probe_temperatures: vec![None, None, None, None],
+            })),
+            Parsed::RealTime(Some(RealTimeData {
+                probe_temperatures: vec![Some(21.0), None, None, None],
+            })),
+            Parsed::RealTime(Some(RealTimeData {
+                probe_temperatures: vec![Some(22.5), Some(19.8), None, None],
+            })),
+            Parsed::RealTime(Some(RealTimeData {
+                probe_temperatures: vec![Some(35.0), None, None, None],
+            })),
+            Parsed::SettingResult(Some(SettingResult::SilencePressed { probe: None })),
+        ]
+    );
+}
+
+/// Setting a target on a probe which the device doesn't have is acknowledged as a failure.
+#[test]
+fn replay_invalid_probe() {
+    assert_eq!(
+        replay(&[Frame::SettingResult(&[0xFF, 0x01, 0x05, 0x00, 0x00, 0x00])]),
+        vec![Parsed::SettingResult(Some(
+            SettingResult::AcknowledgeCommand {
+                command_id: 0x01,
+                success: false,
+                status: AckStatus::InvalidProbe,
+            }
+        ))]
+    );
+}
+
+/// Truncated frames are skipped rather than misparsed.
+#[test]
+fn replay_truncated() {
+    assert_eq!(
+        replay(&[
+            Frame::SettingResult(&[0x24, 0x5B, 0x17]),
+            Frame::RealTime(&[0xD2, 0x00, 0xF6]),
+        ]),
+        vec![Parsed::SettingResult(None), Parsed::RealTime(None)]
+    );
+}
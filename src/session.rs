@@ -0,0 +1,571 @@
+//! A managed connection to a device, which takes care of discovery, connection, authentication and
+//! reconnecting.
+
+use crate::{
+    scan, wait_for_services_resolved, with_reconstructed_times, BBQDevice, BBQEvent, DeviceProfile,
+    DeviceSnapshot, Error, HistoryData, RealTimeData, SettingResult, TemperatureUnit,
+};
+use bluez_async::{BluetoothSession, DeviceId};
+use futures::stream::{self, Stream, StreamExt};
+use log::{info, warn};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+
+/// The default for `SessionOptions::event_buffer_size`.
+pub const DEFAULT_EVENT_BUFFER_SIZE: usize = 64;
+
+/// How long to wait for the device to finish sending its history when filling a gap, in case it
+/// never sends the empty frame which marks the end.
+const HISTORY_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Options for a `SessionManager`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SessionOptions {
+    /// How long to scan for each time when looking for a device.
+    pub scan_duration: Duration,
+    /// How long to wait before trying to reconnect after the connection is lost or fails, or before
+    /// scanning again after no device is found.
+    pub retry_delay: Duration,
+    /// How many events are buffered for each consumer of `SessionManager::events`. If a consumer
+    /// falls this far behind then the oldest events it hasn't received yet are dropped, and a
+    /// message is logged saying how many. A size of 0 is treated as 1.
+    pub event_buffer_size: usize,
+    /// The profile to use for the device, or `None` to choose it from `KNOWN_PROFILES` based on the
+    /// device's name.
+    pub profile: Option<DeviceProfile>,
+    /// If set, the interval at which the device records history. After reconnecting, the history is
+    /// downloaded and the readings recorded while disconnected are sent as `SessionEvent::Backfill`.
+    ///
+    /// No command to request history is known for `DeviceProfile::STANDARD`, so this needs a
+    /// `profile` with `request_history_command`. If downloading the history fails, a warning is
+    /// logged and the session carries on without filling the gap.
+    pub fill_gaps_with_history: Option<Duration>,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            scan_duration: Duration::from_secs(5),
+            retry_delay: Duration::from_secs(5),
+            event_buffer_size: DEFAULT_EVENT_BUFFER_SIZE,
+            profile: None,
+            fill_gaps_with_history: None,
+        }
+    }
+}
+
+/// The state of a `SessionManager`'s connection.
+///
+/// The manager starts by `Scanning` for a compatible device. If none is found, or scanning fails,
+/// it is `WaitingToRescan` for the retry delay before `Scanning` again. Once it finds a device it
+/// moves on to `Connecting` to it. Once connected and authenticated it is `Connected` until the device
+/// disconnects, or if connecting fails, at which point it is `WaitingToReconnect` for the retry
+/// delay before `Connecting` to the same device again.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SessionState {
+    /// Looking for a compatible device.
+    Scanning,
+    /// Waiting before scanning again, because no device was found.
+    WaitingToRescan,
+    /// Connecting to and authenticating with the device.
+    Connecting,
+    /// Connected to the device, and receiving readings.
+    Connected,
+    /// Waiting before trying to connect again.
+    WaitingToReconnect,
+}
+
+/// An event from a `SessionManager`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SessionEvent {
+    /// The state of the connection has changed.
+    StateChanged(SessionState),
+    /// A reading from the device.
+    RealTime(RealTimeData),
+    /// A setting result from the device.
+    SettingResult(SettingResult),
+    /// A reading recorded by the device while it was disconnected, from its history, if
+    /// `SessionOptions::fill_gaps_with_history` is set.
+    ///
+    /// History records don't carry timestamps, so `time` is estimated from the history interval
+    /// working back from when the history was downloaded, as `with_reconstructed_times` does. Only
+    /// records estimated to be after the last reading before the disconnection are sent. They are
+    /// sent oldest first after reconnecting, before the `StateChanged(SessionState::Connected)`
+    /// event, so all of them come before any live readings from the new connection.
+    Backfill {
+        time: SystemTime,
+        reading: RealTimeData,
+    },
+}
+
+/// Something which happened while in some `SessionState`, which may cause a transition.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum LifecycleEvent {
+    DeviceFound,
+    NoDeviceFound,
+    Connected,
+    ConnectFailed,
+    Disconnected,
+    RetryDelayElapsed,
+}
+
+/// Return the state to move to after the given event happens in the given state.
+fn next_state(state: SessionState, event: LifecycleEvent) -> SessionState {
+    match (state, event) {
+        (SessionState::Scanning, LifecycleEvent::DeviceFound) => SessionState::Connecting,
+        (SessionState::Scanning, LifecycleEvent::NoDeviceFound) => SessionState::WaitingToRescan,
+        (SessionState::WaitingToRescan, LifecycleEvent::RetryDelayElapsed) => {
+            SessionState::Scanning
+        }
+        (SessionState::Connecting, LifecycleEvent::Connected) => SessionState::Connected,
+        (SessionState::Connecting, LifecycleEvent::ConnectFailed)
+        | (SessionState::Connected, LifecycleEvent::Disconnected) => {
+            SessionState::WaitingToReconnect
+        }
+        (SessionState::WaitingToReconnect, LifecycleEvent::RetryDelayElapsed) => {
+            SessionState::Connecting
+        }
+        (state, _) => state,
+    }
+}
+
+/// A managed connection to the first compatible device found, which reconnects automatically if
+/// the connection is lost.
+///
+/// Readings, setting results and changes of connection state are all available from `events`. The
+/// targets, labels and display unit set through the manager are restored to the device after it
+/// reconnects.
+/// With `SessionOptions::fill_gaps_with_history`, the readings missed while disconnected are also
+/// filled in from the device's history.
+///
+/// The connection is managed by a background task, which is stopped when the manager is dropped.
+#[derive(Debug)]
+pub struct SessionManager {
+    device: Arc<Mutex<Option<BBQDevice>>>,
+    state: Arc<Mutex<SessionState>>,
+    events: broadcast::Sender<SessionEvent>,
+    task: JoinHandle<()>,
+}
+
+impl SessionManager {
+    /// Start a new Bluetooth session and begin looking for a device.
+    ///
+    /// This must be called from within a Tokio runtime.
+    pub async fn start(options: SessionOptions) -> Result<Self, Error> {
+        let (_, bt_session) = BluetoothSession::new().await?;
+        let device = Arc::new(Mutex::new(None));
+        let state = Arc::new(Mutex::new(SessionState::Scanning));
+        let events = event_channel(options.event_buffer_size);
+        let task = tokio::spawn(run(
+            bt_session,
+            options,
+            device.clone(),
+            state.clone(),
+            events.clone(),
+        ));
+        Ok(Self {
+            device,
+            state,
+            events,
+            task,
+        })
+    }
+
+    /// Get a stream of all events from now on. If the consumer falls more than
+    /// `SessionOptions::event_buffer_size` events behind then the oldest are dropped.
+    pub fn events(&self) -> impl Stream<Item = SessionEvent> {
+        receive_events(self.events.subscribe())
+    }
+
+    /// Get the current state of the connection.
+    pub fn state(&self) -> SessionState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Get the device, if it is currently connected.
+    pub fn device(&self) -> Option<BBQDevice> {
+        self.device.lock().unwrap().clone()
+    }
+
+    fn connected_device(&self) -> Result<BBQDevice, Error> {
+        self.device().ok_or(Error::NotConnected)
+    }
+
+    /// Like `BBQDevice::set_temperature_unit`, or `Error::NotConnected` if not connected.
+    pub async fn set_temperature_unit(&self, unit: TemperatureUnit) -> Result<(), Error> {
+        Ok(self.connected_device()?.set_temperature_unit(unit).await?)
+    }
+
+    /// Like `BBQDevice::set_target_range`, or `Error::NotConnected` if not connected.
+    pub async fn set_target_range(&self, probe: u8, range: Range<f32>) -> Result<(), Error> {
+        self.connected_device()?
+            .set_target_range(probe, range)
+            .await
+    }
+
+    /// Like `BBQDevice::set_target_temp`, or `Error::NotConnected` if not connected.
+    pub async fn set_target_temp(&self, probe: u8, target: f32) -> Result<(), Error> {
+        self.connected_device()?
+            .set_target_temp(probe, target)
+            .await
+    }
+
+    /// Like `BBQDevice::remove_target`, or `Error::NotConnected` if not connected.
+    pub async fn remove_target(&self, probe: u8) -> Result<(), Error> {
+        self.connected_device()?.remove_target(probe).await
+    }
+
+    /// Like `BBQDevice::silence_alarm`, or `Error::NotConnected` if not connected.
+    pub async fn silence_alarm(&self) -> Result<(), Error> {
+        Ok(self.connected_device()?.silence_alarm().await?)
+    }
+}
+
+impl Drop for SessionManager {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Create a broadcast channel for events with the given buffer size, or 1 if it is 0 as
+/// `broadcast::channel` doesn't allow that.
+fn event_channel<T: Clone>(buffer_size: usize) -> broadcast::Sender<T> {
+    broadcast::channel(buffer_size.max(1)).0
+}
+
+/// Turn the given receiver into a stream, skipping over any events which were dropped because it
+/// fell behind.
+fn receive_events<T: Clone>(receiver: broadcast::Receiver<T>) -> impl Stream<Item = T> {
+    stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => return Some((event, receiver)),
+                Err(RecvError::Lagged(count)) => info!("Dropped {} session events", count),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+/// Run the connection state machine until the task is aborted.
+async fn run(
+    bt_session: BluetoothSession,
+    options: SessionOptions,
+    device: Arc<Mutex<Option<BBQDevice>>>,
+    shared_state: Arc<Mutex<SessionState>>,
+    events: broadcast::Sender<SessionEvent>,
+) {
+    let mut state = SessionState::Scanning;
+    let mut device_id = None;
+    let mut snapshot = None;
+    // When the last live reading was received, to know where a gap in readings starts.
+    let mut last_reading_time = None;
+    // Nobody can be subscribed yet, so there's no point sending the initial state.
+    loop {
+        let event = match state {
+            SessionState::Scanning => match scan(&bt_session, options.scan_duration).await {
+                Ok(devices) if !devices.is_empty() => {
+                    device_id = Some(devices[0].id.clone());
+                    LifecycleEvent::DeviceFound
+                }
+                Ok(_) => LifecycleEvent::NoDeviceFound,
+                Err(e) => {
+                    warn!("Failed to scan for devices: {}", e);
+                    LifecycleEvent::NoDeviceFound
+                }
+            },
+            SessionState::Connecting => {
+                let id = device_id.clone().unwrap();
+                match connect(&bt_session, id, options.profile.clone(), snapshot.clone()).await {
+                    Ok(connected) => {
+                        if let (Some(interval), Some(since)) =
+                            (options.fill_gaps_with_history, last_reading_time)
+                        {
+                            if let Err(e) = backfill(&connected, interval, since, &events).await {
+                                warn!("Failed to fill gap with history: {}", e);
+                            }
+                        }
+                        *device.lock().unwrap() = Some(connected);
+                        LifecycleEvent::Connected
+                    }
+                    Err(e) => {
+                        warn!("Failed to connect: {}", e);
+                        LifecycleEvent::ConnectFailed
+                    }
+                }
+            }
+            SessionState::Connected => {
+                let connected = device.lock().unwrap().clone().unwrap();
+                if let Err(e) = forward_events(&connected, &events, &mut last_reading_time).await {
+                    warn!("Lost connection: {}", e);
+                }
+                *device.lock().unwrap() = None;
+                snapshot = Some(connected.snapshot());
+                LifecycleEvent::Disconnected
+            }
+            SessionState::WaitingToRescan | SessionState::WaitingToReconnect => {
+                tokio::time::sleep(options.retry_delay).await;
+                LifecycleEvent::RetryDelayElapsed
+            }
+        };
+        let new_state = next_state(state, event);
+        if new_state != state {
+            state = new_state;
+            *shared_state.lock().unwrap() = state;
+            // It doesn't matter if nobody is listening.
+            let _ = events.send(SessionEvent::StateChanged(state));
+        }
+    }
+}
+
+/// Connect to and authenticate with the given device using the given profile if any, and restore the
+/// given snapshot if any.
+async fn connect(
+    bt_session: &BluetoothSession,
+    id: DeviceId,
+    profile: Option<DeviceProfile>,
+    snapshot: Option<DeviceSnapshot>,
+) -> Result<BBQDevice, Error> {
+    bt_session.connect(&id).await?;
+    wait_for_services_resolved(bt_session, &id).await?;
+    let device = BBQDevice::new(bt_session.clone(), id).await?;
+    if let Some(profile) = profile {
+        device.set_profile(profile);
+    }
+    device.authenticate().await?;
+    if let Some(snapshot) = snapshot {
+        device.restore_snapshot(snapshot).await?;
+    }
+    Ok(device)
+}
+
+/// Download the history from the given device, and send the readings from it which were recorded
+/// after the given time as `SessionEvent::Backfill`.
+async fn backfill(
+    device: &BBQDevice,
+    interval: Duration,
+    since: SystemTime,
+    events: &broadcast::Sender<SessionEvent>,
+) -> Result<(), Error> {
+    let history = device.history().await?;
+    device.request_history().await?;
+    let records = history
+        .take_until(tokio::time::sleep(HISTORY_DOWNLOAD_TIMEOUT))
+        .collect()
+        .await;
+    let gap = gap_readings(records, interval, since, SystemTime::now());
+    info!("Filling gap with {} readings from history", gap.len());
+    for (time, reading) in gap {
+        let _ = events.send(SessionEvent::Backfill { time, reading });
+    }
+    Ok(())
+}
+
+/// Given the history records downloaded at `downloaded_at`, oldest first, return those estimated to
+/// have been recorded after `since` with their estimated times.
+fn gap_readings(
+    records: Vec<HistoryData>,
+    interval: Duration,
+    since: SystemTime,
+    downloaded_at: SystemTime,
+) -> Vec<(SystemTime, RealTimeData)> {
+    with_reconstructed_times(records, interval, downloaded_at)
+        .into_iter()
+        .filter(|(time, _)| *time > since)
+        .map(|(time, record)| {
+            let reading = RealTimeData {
+                probe_temperatures: record.probe_temperatures,
+            };
+            (time, reading)
+        })
+        .collect()
+}
+
+/// Forward readings and setting results from the given device until it disconnects, recording when
+/// the last reading was received.
+async fn forward_events(
+    device: &BBQDevice,
+    events: &broadcast::Sender<SessionEvent>,
+    last_reading_time: &mut Option<SystemTime>,
+) -> Result<(), Error> {
+    let disconnections = device.on_disconnect().await?.map(|()| None);
+    let device_events = device.events().await?.map(|event| {
+        Some(match event {
+            BBQEvent::RealTime(reading) => SessionEvent::RealTime(reading),
+            BBQEvent::Setting(result) => SessionEvent::SettingResult(result),
+        })
+    });
+    device.enable_real_time_data(true).await?;
+    let incoming = stream::select(disconnections, device_events);
+    futures::pin_mut!(incoming);
+    while let Some(Some(event)) = incoming.next().await {
+        if let SessionEvent::RealTime(_) = event {
+            *last_reading_time = Some(SystemTime::now());
+        }
+        let _ = events.send(event);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn run_events(events: &[LifecycleEvent]) -> Vec<SessionState> {
+        events
+            .iter()
+            .scan(SessionState::Scanning, |state, &event| {
+                *state = next_state(*state, event);
+                Some(*state)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn connect_after_scanning() {
+        assert_eq!(
+            run_events(&[
+                LifecycleEvent::NoDeviceFound,
+                LifecycleEvent::RetryDelayElapsed,
+                LifecycleEvent::DeviceFound,
+                LifecycleEvent::Connected,
+            ]),
+            vec![
+                SessionState::WaitingToRescan,
+                SessionState::Scanning,
+                SessionState::Connecting,
+                SessionState::Connected,
+            ]
+        );
+    }
+
+    #[test]
+    fn wait_before_rescanning() {
+        assert_eq!(
+            run_events(&[
+                LifecycleEvent::NoDeviceFound,
+                LifecycleEvent::DeviceFound,
+                LifecycleEvent::RetryDelayElapsed,
+                LifecycleEvent::NoDeviceFound,
+            ]),
+            vec![
+                SessionState::WaitingToRescan,
+                SessionState::WaitingToRescan,
+                SessionState::Scanning,
+                SessionState::WaitingToRescan,
+            ]
+        );
+    }
+
+    #[test]
+    fn reconnect_after_disconnect() {
+        assert_eq!(
+            run_events(&[
+                LifecycleEvent::DeviceFound,
+                LifecycleEvent::Connected,
+                LifecycleEvent::Disconnected,
+                LifecycleEvent::RetryDelayElapsed,
+                LifecycleEvent::ConnectFailed,
+                LifecycleEvent::RetryDelayElapsed,
+                LifecycleEvent::Connected,
+            ]),
+            vec![
+                SessionState::Connecting,
+                SessionState::Connected,
+                SessionState::WaitingToReconnect,
+                SessionState::Connecting,
+                SessionState::WaitingToReconnect,
+                SessionState::Connecting,
+                SessionState::Connected,
+            ]
+        );
+    }
+
+    #[test]
+    fn unexpected_events_ignored() {
+        assert_eq!(
+            run_events(&[LifecycleEvent::Disconnected, LifecycleEvent::Connected]),
+            vec![SessionState::Scanning, SessionState::Scanning]
+        );
+        assert_eq!(
+            next_state(SessionState::Connected, LifecycleEvent::RetryDelayElapsed),
+            SessionState::Connected
+        );
+    }
+
+    #[test]
+    fn gap_readings_after_last_live_reading() {
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let reading = |temperature| RealTimeData {
+            probe_temperatures: vec![Some(temperature), None],
+        };
+        let records = [40.0, 41.0, 42.0, 43.0]
+            .iter()
+            .map(|&temperature| HistoryData {
+                probe_temperatures: reading(temperature).probe_temperatures,
+            })
+            .collect();
+        // The last live reading was at 1000s, and the history was downloaded at 1180s, so the
+        // record from 1000s had already been received live.
+        assert_eq!(
+            gap_readings(records, Duration::from_secs(60), at(1000), at(1180)),
+            vec![
+                (at(1060), reading(41.0)),
+                (at(1120), reading(42.0)),
+                (at(1180), reading(43.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn gap_readings_none_new() {
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let records = vec![HistoryData {
+            probe_temperatures: vec![Some(40.0)],
+        }];
+        assert_eq!(
+            gap_readings(records, Duration::from_secs(60), at(1000), at(1000)),
+            vec![]
+        );
+    }
+
+    /// Send the given number of events to a slow consumer with the given buffer size, and return
+    /// how many it receives.
+    fn received_after_burst(buffer_size: usize, count: usize) -> usize {
+        let sender = event_channel(buffer_size);
+        let receiver = sender.subscribe();
+        for i in 0..count {
+            sender.send(i).unwrap();
+        }
+        drop(sender);
+        let received: Vec<usize> = block_on(receive_events(receiver).collect());
+        // Whatever is dropped, the most recent events should be kept in order.
+        assert_eq!(
+            received,
+            (count - received.len()..count).collect::<Vec<_>>()
+        );
+        received.len()
+    }
+
+    #[test]
+    fn default_buffer_drops_oldest() {
+        assert_eq!(
+            received_after_burst(DEFAULT_EVENT_BUFFER_SIZE, 100),
+            DEFAULT_EVENT_BUFFER_SIZE
+        );
+    }
+
+    #[test]
+    fn larger_buffer_retains_events() {
+        assert_eq!(received_after_burst(128, 100), 100);
+    }
+
+    #[test]
+    fn zero_buffer_keeps_latest_event() {
+        assert_eq!(received_after_burst(0, 10), 1);
+    }
+}
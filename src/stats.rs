@@ -0,0 +1,142 @@
+//! Statistics over the readings from a cook.
+
+use crate::{target_contains, RealTimeData};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::ops::Range;
+use std::time::{Duration, SystemTime};
+
+/// Statistics about a single probe, as computed by `CookStats`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProbeStats {
+    /// The lowest temperature reported by the probe, if it has been connected.
+    pub min: Option<f32>,
+    /// The highest temperature reported by the probe, if it has been connected.
+    pub max: Option<f32>,
+    /// The number of readings in which the probe was connected.
+    pub count: usize,
+    /// The total time for which the probe was inside its target range.
+    pub time_in_range: Duration,
+    sum: f32,
+    in_range: bool,
+}
+
+impl ProbeStats {
+    /// Return the mean temperature reported by the probe, if it has been connected.
+    pub fn mean(&self) -> Option<f32> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.sum / self.count as f32)
+        }
+    }
+
+    fn update(&mut self, temperature: Option<f32>, target: Option<&Range<f32>>) {
+        self.in_range = match temperature {
+            Some(temperature) => {
+                self.min = Some(self.min.map_or(temperature, |min| min.min(temperature)));
+                self.max = Some(self.max.map_or(temperature, |max| max.max(temperature)));
+                self.count += 1;
+                self.sum += temperature;
+                target.is_some_and(|range| target_contains(range, temperature))
+            }
+            None => false,
+        };
+    }
+}
+
+/// Per-probe statistics over the readings from a cook, for post-cook summaries.
+///
+/// The time in range for each probe is worked out from the times of the readings, counting the time
+/// from each reading inside the target range until the next reading. Note that for a target set
+/// with `BBQDevice::set_target_temp` the range is everything below the target.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CookStats {
+    targets: HashMap<u8, Range<f32>>,
+    probes: Vec<ProbeStats>,
+    last_time: Option<SystemTime>,
+}
+
+impl CookStats {
+    /// Create an empty set of statistics, using the given target range for each probe to work out
+    /// its time in range.
+    pub fn new(targets: HashMap<u8, Range<f32>>) -> Self {
+        Self {
+            targets,
+            ..Default::default()
+        }
+    }
+
+    /// Compute statistics over the given readings, each paired with the time it was received.
+    pub fn from_readings(
+        readings: impl IntoIterator<Item = (SystemTime, RealTimeData)>,
+        targets: HashMap<u8, Range<f32>>,
+    ) -> Self {
+        let mut stats = Self::new(targets);
+        for (time, reading) in readings {
+            stats.update(time, &reading);
+        }
+        stats
+    }
+
+    /// Update the statistics with a reading received at the given time.
+    pub fn update(&mut self, time: SystemTime, reading: &RealTimeData) {
+        let elapsed = self
+            .last_time
+            .and_then(|last_time| time.duration_since(last_time).ok())
+            .unwrap_or_default();
+        self.last_time = Some(time);
+        if self.probes.len() < reading.probe_temperatures.len() {
+            self.probes
+                .resize(reading.probe_temperatures.len(), ProbeStats::default());
+        }
+        let targets = &self.targets;
+        for (index, probe) in self.probes.iter_mut().enumerate() {
+            if probe.in_range {
+                probe.time_in_range += elapsed;
+            }
+            let temperature = reading.probe_temperatures.get(index).copied().flatten();
+            let target = u8::try_from(index)
+                .ok()
+                .and_then(|index| targets.get(&index));
+            probe.update(temperature, target);
+        }
+    }
+
+    /// Get the statistics for each probe.
+    pub fn probes(&self) -> &[ProbeStats] {
+        &self.probes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_over_cook() {
+        let mut targets = HashMap::new();
+        targets.insert(0, 50.0..60.0);
+        let readings = [Some(45.0), Some(55.0), Some(58.0), Some(65.0), None]
+            .iter()
+            .enumerate()
+            .map(|(i, &temperature)| {
+                (
+                    SystemTime::UNIX_EPOCH + Duration::from_secs(10 * i as u64),
+                    RealTimeData {
+                        probe_temperatures: vec![temperature, None],
+                    },
+                )
+            });
+        let stats = CookStats::from_readings(readings, targets);
+        let probes = stats.probes();
+        assert_eq!(probes.len(), 2);
+        assert_eq!(probes[0].min, Some(45.0));
+        assert_eq!(probes[0].max, Some(65.0));
+        assert_eq!(probes[0].count, 4);
+        assert_eq!(probes[0].mean(), Some(55.75));
+        assert_eq!(probes[0].time_in_range, Duration::from_secs(20));
+        assert_eq!(probes[1], ProbeStats::default());
+        assert_eq!(probes[1].mean(), None);
+    }
+}